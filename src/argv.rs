@@ -0,0 +1,69 @@
+use serde_json::Value;
+
+/// `/argv` options documented for Umi-OCR v2, with a short description.
+pub const KNOWN_COMMANDS: &[(&str, &str)] = &[
+    ("--show", "Show the main window"),
+    ("--hide", "Hide the main window"),
+    ("--quit", "Quit Umi-OCR"),
+    ("--all_pages", "List open tabs"),
+    ("--add_page", "Open a tab from a template index"),
+    ("--del_page", "Close the tab with an index"),
+    ("--all_modules", "List the modules that can be called"),
+    ("--call_py", "Call a function of a Python module"),
+    ("--call_qml", "Call a function of a QML module"),
+    ("--screenshot", "Take a screenshot and OCR it"),
+    ("--clipboard", "OCR an image from the clipboard"),
+    ("--path", "OCR image files at paths"),
+    ("--output", "Write OCR results to a file"),
+];
+
+/// One invocation of Umi-OCR's `/argv` endpoint, built flag by flag.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ArgvCommand(Vec<String>);
+
+impl ArgvCommand {
+    fn flag(flag: &str) -> Self {
+        Self(vec![flag.to_string()])
+    }
+
+    pub fn help() -> Self {
+        Self::flag("--help")
+    }
+
+    pub fn all_pages() -> Self {
+        Self::flag("--all_pages")
+    }
+
+    pub fn all_modules() -> Self {
+        Self::flag("--all_modules")
+    }
+
+    pub fn add_page(template: u16) -> Self {
+        Self::flag("--add_page").arg(template)
+    }
+
+    pub fn del_page(index: u16) -> Self {
+        Self::flag("--del_page").arg(index)
+    }
+
+    pub fn call_qml(object: &str) -> Self {
+        Self::flag("--call_qml").arg(object)
+    }
+
+    pub fn func(self, name: &str) -> Self {
+        self.arg("--func").arg(name)
+    }
+
+    pub fn arg(mut self, value: impl ToString) -> Self {
+        self.0.push(value.to_string());
+        self
+    }
+
+    pub fn json_arg(self, value: Value) -> Self {
+        self.arg(value)
+    }
+
+    pub fn into_value(self) -> Value {
+        Value::from(self.0)
+    }
+}
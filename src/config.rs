@@ -0,0 +1,403 @@
+use anyhow::{anyhow, Result};
+use serde_json::Value;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::fs;
+use tokio_util::sync::CancellationToken;
+
+const VERIFY_RETRIES: u32 = 2;
+const WATCH_GRACE_MS: u64 = 500;
+const DELAY_MS: u64 = 1000;
+const VALIDATE_RETRIES: u32 = 2;
+const PAGE_TIMEOUT_FLOOR: Duration = Duration::from_secs(30);
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct ConfigFileArgs {
+    /// JSON file with defaults for the phase limits, keyed by flag name
+    /// (e.g. `{"watch_timeout": 600, "open_retries": 1}`)
+    #[arg(long, value_name = "PATH", env = "UMI_HTTP_CONFIG")]
+    pub config: Option<PathBuf>,
+}
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct ConfigArgs {
+    #[command(flatten)]
+    pub file: ConfigFileArgs,
+    /// Seconds to wait for the BatchDOC tab to open
+    #[arg(long, value_name = "SECONDS", env = "UMI_HTTP_OPEN_TIMEOUT")]
+    pub open_timeout: Option<u64>,
+    /// Times to retry opening the BatchDOC tab
+    #[arg(long, value_name = "COUNT", env = "UMI_HTTP_OPEN_RETRIES")]
+    pub open_retries: Option<u32>,
+    /// Seconds to wait for each tab listing while verifying the BatchDOC tab
+    #[arg(long, value_name = "SECONDS", env = "UMI_HTTP_VERIFY_TIMEOUT")]
+    pub verify_timeout: Option<u64>,
+    /// Times to re-list tabs before giving up on the BatchDOC tab [default: 2]
+    #[arg(long, value_name = "COUNT", env = "UMI_HTTP_VERIFY_RETRIES")]
+    pub verify_retries: Option<u32>,
+    /// Seconds to wait for documents to be added
+    #[arg(long, value_name = "SECONDS", env = "UMI_HTTP_ADD_TIMEOUT")]
+    pub add_timeout: Option<u64>,
+    /// Times to retry adding documents
+    #[arg(long, value_name = "COUNT", env = "UMI_HTTP_ADD_RETRIES")]
+    pub add_retries: Option<u32>,
+    /// Seconds to wait for processing to start
+    #[arg(long, value_name = "SECONDS", env = "UMI_HTTP_START_TIMEOUT")]
+    pub start_timeout: Option<u64>,
+    /// Times to retry starting processing
+    #[arg(long, value_name = "COUNT", env = "UMI_HTTP_START_RETRIES")]
+    pub start_retries: Option<u32>,
+    /// Milliseconds to pause after closing each BatchDOC tab, so Umi-OCR has
+    /// finished tearing it down before the next one is opened [default: 1000]
+    #[arg(long, value_name = "MS", env = "UMI_HTTP_CLOSE_DELAY")]
+    pub close_delay: Option<u64>,
+    /// Milliseconds to pause after opening the BatchDOC tab, so it is listed
+    /// before it is verified [default: 1000]
+    #[arg(long, value_name = "MS", env = "UMI_HTTP_OPEN_DELAY")]
+    pub open_delay: Option<u64>,
+    /// Milliseconds to pause after adding documents, so they are loaded
+    /// before processing starts [default: 1000]
+    #[arg(long, value_name = "MS", env = "UMI_HTTP_ADD_DELAY")]
+    pub add_delay: Option<u64>,
+    /// Milliseconds to pause before each retry [default: 1000]
+    #[arg(long, value_name = "MS", env = "UMI_HTTP_RETRY_DELAY")]
+    pub retry_delay: Option<u64>,
+    /// Seconds to allow per input page when its page count is known, instead
+    /// of --watch-timeout (30s are added on top)
+    #[arg(long, value_name = "SECONDS", env = "UMI_HTTP_TIMEOUT_PER_PAGE")]
+    pub timeout_per_page: Option<u64>,
+    /// Total retries allowed across all phases and documents in this run
+    #[arg(long, value_name = "COUNT", env = "UMI_HTTP_MAX_RETRIES")]
+    pub max_retries: Option<usize>,
+    #[command(flatten)]
+    pub watch: WatchConfigArgs,
+}
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct WatchConfigArgs {
+    /// Give up waiting for an output after this many seconds
+    #[arg(long, value_name = "SECONDS", env = "UMI_HTTP_WATCH_TIMEOUT")]
+    pub watch_timeout: Option<u64>,
+    /// Times to restart watching for an output after an error or timeout
+    #[arg(long, value_name = "COUNT", env = "UMI_HTTP_WATCH_RETRIES")]
+    pub watch_retries: Option<u32>,
+    /// Milliseconds to wait for the output size to settle after it is detected [default: 500]
+    #[arg(long, value_name = "MS", env = "UMI_HTTP_WATCH_GRACE")]
+    pub watch_grace: Option<u64>,
+    /// Milliseconds between checks for an output, starting once processing
+    /// has been started [default: 1000]
+    #[arg(long, value_name = "MS", env = "UMI_HTTP_POLL_INTERVAL")]
+    pub poll_interval: Option<u64>,
+    /// Times to let an output that fails validation settle again before
+    /// giving up on it [default: 2]
+    #[arg(long, value_name = "COUNT", env = "UMI_HTTP_VALIDATE_RETRIES")]
+    pub validate_retries: Option<u32>,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PhaseLimits {
+    pub timeout: Option<Duration>,
+    pub retries: u32,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RetryBudget(Option<(usize, Arc<AtomicUsize>)>);
+
+impl RetryBudget {
+    pub fn new(limit: Option<usize>) -> Self {
+        Self(limit.map(|limit| (limit, Arc::new(AtomicUsize::new(limit)))))
+    }
+
+    pub fn take(&self) -> Result<()> {
+        let Some((limit, remaining)) = &self.0 else {
+            return Ok(());
+        };
+        remaining
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |left| {
+                left.checked_sub(1)
+            })
+            .map(|_| ())
+            .map_err(|_| anyhow!("Retry budget of {} exhausted", limit))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RunConfig {
+    pub open: PhaseLimits,
+    pub verify: PhaseLimits,
+    pub add: PhaseLimits,
+    pub start: PhaseLimits,
+    pub watch: PhaseLimits,
+    pub watch_grace: Duration,
+    pub poll_interval: Duration,
+    pub validate_retries: u32,
+    pub close_delay: Duration,
+    pub open_delay: Duration,
+    pub add_delay: Duration,
+    pub retry_delay: Duration,
+    pub timeout_per_page: Option<Duration>,
+    pub retry_budget: RetryBudget,
+    pub cancel: CancellationToken,
+}
+
+impl Default for RunConfig {
+    fn default() -> Self {
+        Self {
+            open: PhaseLimits::default(),
+            verify: PhaseLimits {
+                timeout: None,
+                retries: VERIFY_RETRIES,
+            },
+            add: PhaseLimits::default(),
+            start: PhaseLimits::default(),
+            watch: PhaseLimits::default(),
+            watch_grace: Duration::from_millis(WATCH_GRACE_MS),
+            poll_interval: Duration::from_millis(DELAY_MS),
+            validate_retries: VALIDATE_RETRIES,
+            close_delay: Duration::from_millis(DELAY_MS),
+            open_delay: Duration::from_millis(DELAY_MS),
+            add_delay: Duration::from_millis(DELAY_MS),
+            retry_delay: Duration::from_millis(DELAY_MS),
+            timeout_per_page: None,
+            retry_budget: RetryBudget::default(),
+            cancel: CancellationToken::new(),
+        }
+    }
+}
+
+struct ConfigFile(Value);
+
+impl ConfigFile {
+    async fn load(path: Option<&PathBuf>) -> Result<Self> {
+        let Some(path) = path else {
+            return Ok(Self(Value::Null));
+        };
+        let contents = fs::read_to_string(path)
+            .await
+            .map_err(|error| anyhow!("Error reading config {}: {}", path.display(), error))?;
+        let value: Value = serde_json::from_str(&contents)
+            .map_err(|error| anyhow!("Error parsing config {}: {}", path.display(), error))?;
+        if !value.is_object() {
+            return Err(anyhow!("Config {} must be a JSON object", path.display()));
+        }
+        Ok(Self(value))
+    }
+
+    fn get(&self, key: &str) -> Result<Option<u64>> {
+        match self.0.get(key) {
+            None | Some(Value::Null) => Ok(None),
+            Some(value) => value
+                .as_u64()
+                .map(Some)
+                .ok_or_else(|| anyhow!("Config key {} must be a non-negative integer", key)),
+        }
+    }
+
+    fn millis(&self, key: &str, value: Option<u64>, default: Duration) -> Result<Duration> {
+        let value = match value {
+            Some(value) => Some(value),
+            None => self.get(key)?,
+        };
+        Ok(value.map(Duration::from_millis).unwrap_or(default))
+    }
+
+    fn phase(
+        &self,
+        name: &str,
+        timeout: Option<u64>,
+        retries: Option<u32>,
+        default: PhaseLimits,
+    ) -> Result<PhaseLimits> {
+        let timeout = match timeout {
+            Some(timeout) => Some(timeout),
+            None => self.get(&format!("{}_timeout", name))?,
+        };
+        let retries = match retries {
+            Some(retries) => Some(retries),
+            None => self
+                .get(&format!("{}_retries", name))?
+                .map(u32::try_from)
+                .transpose()?,
+        };
+        Ok(PhaseLimits {
+            timeout: timeout.map(Duration::from_secs).or(default.timeout),
+            retries: retries.unwrap_or(default.retries),
+        })
+    }
+}
+
+impl RunConfig {
+    /// Watch limits for `pages` pages: the per-page budget when both are
+    /// known, the flat watch timeout otherwise. A budget too large to
+    /// represent means no timeout.
+    pub fn watch_for(&self, pages: Option<usize>) -> PhaseLimits {
+        match (self.timeout_per_page, pages) {
+            (Some(per_page), Some(pages)) => PhaseLimits {
+                timeout: u32::try_from(pages)
+                    .ok()
+                    .and_then(|pages| per_page.checked_mul(pages))
+                    .and_then(|budget| PAGE_TIMEOUT_FLOOR.checked_add(budget)),
+                ..self.watch
+            },
+            _ => self.watch,
+        }
+    }
+
+    fn with_watch(file: &ConfigFile, args: &WatchConfigArgs) -> Result<Self> {
+        let default = Self::default();
+        Ok(Self {
+            watch: file.phase(
+                "watch",
+                args.watch_timeout,
+                args.watch_retries,
+                default.watch,
+            )?,
+            watch_grace: file.millis("watch_grace", args.watch_grace, default.watch_grace)?,
+            poll_interval: file.millis(
+                "poll_interval",
+                args.poll_interval,
+                default.poll_interval,
+            )?,
+            validate_retries: match args.validate_retries {
+                Some(retries) => retries,
+                None => file
+                    .get("validate_retries")?
+                    .map(u32::try_from)
+                    .transpose()?
+                    .unwrap_or(default.validate_retries),
+            },
+            ..default
+        })
+    }
+
+    pub async fn from_watch_args(file: &ConfigFileArgs, args: &WatchConfigArgs) -> Result<Self> {
+        let file = ConfigFile::load(file.config.as_ref()).await?;
+        Self::with_watch(&file, args)
+    }
+
+    pub async fn from_args(args: &ConfigArgs) -> Result<Self> {
+        let file = ConfigFile::load(args.file.config.as_ref()).await?;
+        let config = Self::with_watch(&file, &args.watch)?;
+        let max_retries = match args.max_retries {
+            Some(max_retries) => Some(max_retries),
+            None => file.get("max_retries")?.map(usize::try_from).transpose()?,
+        };
+        Ok(Self {
+            open: file.phase("open", args.open_timeout, args.open_retries, config.open)?,
+            verify: file.phase(
+                "verify",
+                args.verify_timeout,
+                args.verify_retries,
+                config.verify,
+            )?,
+            add: file.phase("add", args.add_timeout, args.add_retries, config.add)?,
+            start: file.phase(
+                "start",
+                args.start_timeout,
+                args.start_retries,
+                config.start,
+            )?,
+            close_delay: file.millis("close_delay", args.close_delay, config.close_delay)?,
+            open_delay: file.millis("open_delay", args.open_delay, config.open_delay)?,
+            add_delay: file.millis("add_delay", args.add_delay, config.add_delay)?,
+            retry_delay: file.millis("retry_delay", args.retry_delay, config.retry_delay)?,
+            timeout_per_page: match args.timeout_per_page {
+                Some(seconds) => Some(seconds),
+                None => file.get("timeout_per_page")?,
+            }
+            .map(Duration::from_secs),
+            retry_budget: RetryBudget::new(max_retries),
+            ..config
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn retry_budget_without_a_limit_never_runs_out() {
+        let budget = RetryBudget::new(None);
+        for _ in 0..100 {
+            budget.take().unwrap();
+        }
+    }
+
+    #[test]
+    fn retry_budget_is_shared_between_clones() {
+        let budget = RetryBudget::new(Some(2));
+        let clone = budget.clone();
+        budget.take().unwrap();
+        clone.take().unwrap();
+        let error = budget.take().unwrap_err();
+        assert_eq!(error.to_string(), "Retry budget of 2 exhausted");
+        assert!(clone.take().is_err());
+    }
+
+    #[test]
+    fn flags_take_precedence_over_the_config_file() {
+        let file = ConfigFile(json!({"open_timeout": 30, "open_retries": 4}));
+        let limits = file
+            .phase("open", Some(10), None, PhaseLimits::default())
+            .unwrap();
+        assert_eq!(limits.timeout, Some(Duration::from_secs(10)));
+        assert_eq!(limits.retries, 4);
+    }
+
+    #[test]
+    fn defaults_apply_when_neither_flag_nor_file_sets_a_limit() {
+        let file = ConfigFile(Value::Null);
+        let default = RunConfig::default().verify;
+        let limits = file.phase("verify", None, None, default).unwrap();
+        assert_eq!(limits.timeout, None);
+        assert_eq!(limits.retries, VERIFY_RETRIES);
+        let delay = file
+            .millis("retry_delay", None, Duration::from_millis(DELAY_MS))
+            .unwrap();
+        assert_eq!(delay, Duration::from_millis(DELAY_MS));
+    }
+
+    #[test]
+    fn config_file_values_must_be_non_negative_integers() {
+        let file = ConfigFile(json!({"watch_timeout": -1}));
+        assert!(file
+            .phase("watch", None, None, PhaseLimits::default())
+            .is_err());
+    }
+
+    #[test]
+    fn watch_for_allows_time_per_page_on_top_of_the_floor() {
+        let config = RunConfig {
+            watch: PhaseLimits {
+                timeout: Some(Duration::from_secs(600)),
+                retries: 1,
+            },
+            timeout_per_page: Some(Duration::from_secs(10)),
+            ..RunConfig::default()
+        };
+        let limits = config.watch_for(Some(3));
+        assert_eq!(
+            limits.timeout,
+            Some(PAGE_TIMEOUT_FLOOR + Duration::from_secs(30))
+        );
+        assert_eq!(limits.retries, 1);
+        assert_eq!(
+            config.watch_for(None).timeout,
+            Some(Duration::from_secs(600))
+        );
+    }
+
+    #[test]
+    fn watch_for_drops_a_budget_too_large_to_represent() {
+        let config = RunConfig {
+            timeout_per_page: Some(Duration::MAX),
+            ..RunConfig::default()
+        };
+        assert_eq!(config.watch_for(Some(2)).timeout, None);
+        assert_eq!(config.watch_for(Some(1)).timeout, None);
+    }
+}
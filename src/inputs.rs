@@ -0,0 +1,433 @@
+use crate::paths::OutputFormat;
+use crate::{paths, pdf};
+use anyhow::{anyhow, Result};
+use clap::ValueEnum;
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use tokio::fs;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+pub const SUPPORTED_EXTENSIONS: &[&str] = &["pdf", "xps", "epub", "mobi", "fb2", "cbz"];
+const STREAM_CAPACITY: usize = 64;
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct InputArgs {
+    /// Document to process, or a directory of documents (repeatable).
+    /// Append `:FORMAT` (e.g. `scans:txt`) to override `--output-format`
+    /// for it
+    #[arg(short, long, required = true)]
+    pub path: Vec<String>,
+    /// Descend into subdirectories of directory inputs
+    #[arg(short, long)]
+    pub recursive: bool,
+    /// Only process files modified after this time, as an RFC 3339 timestamp
+    /// or a duration before now (e.g. `24h`, `7days`)
+    #[arg(long, value_name = "TIME", value_parser = parse_since)]
+    pub since: Option<SystemTime>,
+    /// Skip inputs whose path or file name matches this glob (repeatable).
+    /// Outputs of earlier runs (`*.layered.pdf`, `*.text.pdf`) are always
+    /// skipped
+    #[arg(long, value_name = "GLOB")]
+    pub exclude: Vec<String>,
+    /// Process a file once for each time it is named instead of collapsing
+    /// inputs that resolve to the same file
+    #[arg(long)]
+    pub allow_duplicates: bool,
+    /// Directory relative inputs are resolved against, so that Umi-OCR is
+    /// always sent absolute paths [default: the current directory]
+    #[arg(long, value_name = "DIR")]
+    pub base_dir: Option<PathBuf>,
+}
+
+fn exclusions(patterns: &[String]) -> Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    builder.add(Glob::new(&format!("*{}", paths::OUTPUT_SUFFIX))?);
+    builder.add(Glob::new(&format!("*{}", paths::TEXT_PDF_SUFFIX))?);
+    for pattern in patterns {
+        let glob = Glob::new(pattern)
+            .map_err(|error| anyhow!("Invalid exclude pattern {}: {}", pattern, error))?;
+        builder.add(glob);
+    }
+    Ok(builder.build()?)
+}
+
+/// Splits a trailing `:FORMAT` off an input. Drive letters are left alone as
+/// what follows their colon is never a format name.
+pub fn split_format(input: &str) -> (&str, Option<OutputFormat>) {
+    match input.rsplit_once(':') {
+        Some((path, format)) if !path.is_empty() => match OutputFormat::from_str(format, true) {
+            Ok(format) => (path, Some(format)),
+            Err(_) => (input, None),
+        },
+        _ => (input, None),
+    }
+}
+
+/// Resolves an input against `--base-dir`, or the current directory if none
+/// is given. Absolute inputs are returned as they are; `.` components are
+/// dropped but `..` is kept, as symlinks make it ambiguous.
+pub fn absolute(args: &InputArgs, path: &str) -> PathBuf {
+    let path = match &args.base_dir {
+        Some(base_dir) => base_dir.join(path),
+        None => PathBuf::from(path),
+    };
+    std::path::absolute(&path).unwrap_or(path)
+}
+
+/// Format given with the most specific input that `source` was resolved from.
+pub fn format_override(args: &InputArgs, source: &Path) -> Option<OutputFormat> {
+    args.path
+        .iter()
+        .filter_map(|input| match split_format(input) {
+            (path, Some(format)) => Some((absolute(args, path), format)),
+            _ => None,
+        })
+        .filter(|(path, _)| source.starts_with(path))
+        .max_by_key(|(path, _)| path.components().count())
+        .map(|(_, format)| format)
+}
+
+/// Whether any input was given with a `:format` suffix.
+pub fn has_format_overrides(args: &InputArgs) -> bool {
+    args.path
+        .iter()
+        .any(|input| split_format(input).1.is_some())
+}
+
+fn is_excluded(exclude: &GlobSet, base_dir: &Path, path: &Path) -> bool {
+    exclude.is_match(path)
+        || path
+            .strip_prefix(base_dir)
+            .is_ok_and(|relative| exclude.is_match(relative))
+        || path.file_name().is_some_and(|name| exclude.is_match(name))
+}
+
+fn parse_since(value: &str) -> Result<SystemTime, String> {
+    if let Ok(time) = humantime::parse_rfc3339_weak(value) {
+        return Ok(time);
+    }
+    let duration = humantime::parse_duration(value)
+        .map_err(|_| "expected an RFC 3339 timestamp or a duration like 24h".to_string())?;
+    SystemTime::now()
+        .checked_sub(duration)
+        .ok_or_else(|| "duration reaches too far into the past".to_string())
+}
+
+async fn modified_before(path: &Path, cutoff: SystemTime) -> bool {
+    fs::metadata(path)
+        .await
+        .and_then(|metadata| metadata.modified())
+        .is_ok_and(|modified| modified <= cutoff)
+}
+
+pub fn is_supported(path: &Path) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| {
+            SUPPORTED_EXTENSIONS
+                .iter()
+                .any(|supported| extension.eq_ignore_ascii_case(supported))
+        })
+}
+
+struct Resolver<F> {
+    exclude: GlobSet,
+    base_dir: PathBuf,
+    since: Option<SystemTime>,
+    sender: mpsc::Sender<PathBuf>,
+    on_found: F,
+    unique: Option<HashSet<PathBuf>>,
+    seen: usize,
+    excluded: usize,
+    old: usize,
+    duplicates: usize,
+    found: usize,
+}
+
+impl<F: Fn(&Path)> Resolver<F> {
+    async fn emit(&mut self, path: PathBuf) -> bool {
+        self.seen += 1;
+        if is_excluded(&self.exclude, &self.base_dir, &path) {
+            self.excluded += 1;
+            return true;
+        }
+        if let Some(cutoff) = self.since {
+            if modified_before(&path, cutoff).await {
+                self.old += 1;
+                return true;
+            }
+        }
+        if let Some(unique) = &mut self.unique {
+            let canonical = fs::canonicalize(&path)
+                .await
+                .unwrap_or_else(|_| path.clone());
+            if !unique.insert(canonical) {
+                self.duplicates += 1;
+                return true;
+            }
+        }
+        self.found += 1;
+        (self.on_found)(&path);
+        self.sender.send(path).await.is_ok()
+    }
+
+    async fn collect_dir(&mut self, dir: &Path, recursive: bool) -> Result<bool> {
+        let mut pending = vec![dir.to_path_buf()];
+        while let Some(dir) = pending.pop() {
+            let mut entries = fs::read_dir(&dir)
+                .await
+                .map_err(|error| anyhow!("Error reading directory {}: {}", dir.display(), error))?;
+            let mut files = Vec::new();
+            let mut subdirs = Vec::new();
+            while let Some(entry) = entries.next_entry().await? {
+                let path = entry.path();
+                if entry.file_type().await?.is_dir() {
+                    if recursive {
+                        subdirs.push(path);
+                    }
+                } else if is_supported(&path) {
+                    files.push(path);
+                }
+            }
+            files.sort();
+            for file in files {
+                if !self.emit(file).await {
+                    return Ok(false);
+                }
+            }
+            subdirs.sort();
+            pending.extend(subdirs.into_iter().rev());
+        }
+        Ok(true)
+    }
+
+    async fn run(mut self, paths: Vec<PathBuf>, recursive: bool) -> Result<usize> {
+        for path in paths {
+            let open = if fs::metadata(&path)
+                .await
+                .is_ok_and(|metadata| metadata.is_dir())
+            {
+                self.collect_dir(&path, recursive).await?
+            } else {
+                self.emit(path).await
+            };
+            if !open {
+                break;
+            }
+        }
+        if self.excluded > 0 {
+            println!(
+                "Excluded {} of {} files by pattern.",
+                self.excluded, self.seen
+            );
+        }
+        if let Some(cutoff) = self.since {
+            println!(
+                "Skipped {} of {} files not modified since {}.",
+                self.old,
+                self.seen - self.excluded,
+                humantime::format_rfc3339_seconds(cutoff)
+            );
+        }
+        if self.duplicates > 0 {
+            println!(
+                "Collapsed {} duplicate inputs naming the same file.",
+                self.duplicates
+            );
+        }
+        Ok(self.found)
+    }
+}
+
+/// Inputs discovered in the background as they are consumed, so that large
+/// directories neither sit in memory nor delay the first document.
+pub struct InputStream {
+    receiver: mpsc::Receiver<PathBuf>,
+    task: JoinHandle<Result<usize>>,
+}
+
+impl InputStream {
+    pub async fn next(&mut self) -> Option<PathBuf> {
+        self.receiver.recv().await
+    }
+
+    /// Waits for resolution to end and returns the number of inputs found.
+    /// Call once `next` has returned `None`.
+    pub async fn finish(self) -> Result<usize> {
+        self.task
+            .await
+            .map_err(|error| anyhow!("Error resolving inputs: {}", error))?
+    }
+}
+
+pub fn stream(args: &InputArgs, on_found: impl Fn(&Path) + Send + 'static) -> Result<InputStream> {
+    let (sender, receiver) = mpsc::channel(STREAM_CAPACITY);
+    let resolver = Resolver {
+        exclude: exclusions(&args.exclude)?,
+        base_dir: absolute(args, "."),
+        since: args.since,
+        sender,
+        on_found,
+        unique: (!args.allow_duplicates).then(HashSet::new),
+        seen: 0,
+        excluded: 0,
+        old: 0,
+        duplicates: 0,
+        found: 0,
+    };
+    let paths = args
+        .path
+        .iter()
+        .map(|path| absolute(args, split_format(path).0))
+        .collect();
+    let task = tokio::spawn(resolver.run(paths, args.recursive));
+    Ok(InputStream { receiver, task })
+}
+
+pub async fn resolve(args: &InputArgs) -> Result<Vec<PathBuf>> {
+    let mut stream = stream(args, |_| {})?;
+    let mut inputs = Vec::new();
+    while let Some(input) = stream.next().await {
+        inputs.push(input);
+    }
+    stream.finish().await?;
+    Ok(inputs)
+}
+
+pub async fn check(path: &Path) -> Vec<String> {
+    let metadata = match fs::metadata(path).await {
+        Ok(metadata) => metadata,
+        Err(error) => return vec![format!("cannot be accessed: {}", error)],
+    };
+    if !metadata.is_file() {
+        return vec!["is not a file".to_string()];
+    }
+    if let Err(error) = fs::File::open(path).await {
+        return vec![format!("is not readable: {}", error)];
+    }
+    let mut problems = Vec::new();
+    if !is_supported(path) {
+        problems.push(format!(
+            "has an unsupported type (expected one of: {})",
+            SUPPORTED_EXTENSIONS.join(", ")
+        ));
+    }
+    if path
+        .extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("pdf"))
+    {
+        match pdf::needs_password(path).await {
+            Ok(true) => problems.push("is encrypted and requires a password".to_string()),
+            Ok(false) => {}
+            Err(error) => problems.push(format!("is not a readable PDF: {}", error)),
+        }
+    }
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(paths: &[&str], base_dir: Option<&Path>) -> InputArgs {
+        InputArgs {
+            path: paths.iter().map(|path| path.to_string()).collect(),
+            recursive: false,
+            since: None,
+            exclude: Vec::new(),
+            allow_duplicates: false,
+            base_dir: base_dir.map(Path::to_path_buf),
+        }
+    }
+
+    fn base_dir() -> PathBuf {
+        std::env::temp_dir().join("umi-http-base")
+    }
+
+    #[test]
+    fn split_format_splits_a_known_format() {
+        assert_eq!(
+            split_format("scans:txt"),
+            ("scans", Some(OutputFormat::Txt))
+        );
+        assert_eq!(
+            split_format("a.pdf:Layered-Pdf"),
+            ("a.pdf", Some(OutputFormat::LayeredPdf))
+        );
+    }
+
+    #[test]
+    fn split_format_keeps_inputs_without_a_format() {
+        assert_eq!(split_format("scans"), ("scans", None));
+        assert_eq!(split_format("notes:draft"), ("notes:draft", None));
+        assert_eq!(split_format(":txt"), (":txt", None));
+        assert_eq!(split_format(r"C:\scans"), (r"C:\scans", None));
+        assert_eq!(
+            split_format(r"C:\scans:csv"),
+            (r"C:\scans", Some(OutputFormat::Csv))
+        );
+    }
+
+    #[test]
+    fn absolute_resolves_relative_inputs_against_the_base_dir() {
+        let base = base_dir();
+        let args = args(&[], Some(&base));
+        assert_eq!(
+            absolute(&args, "scans/a.pdf"),
+            base.join("scans").join("a.pdf")
+        );
+        assert_eq!(absolute(&args, "./a.pdf"), base.join("a.pdf"));
+    }
+
+    #[test]
+    fn absolute_resolves_relative_inputs_against_the_current_dir() {
+        let args = args(&[], None);
+        let current = std::env::current_dir().unwrap();
+        assert_eq!(absolute(&args, "a.pdf"), current.join("a.pdf"));
+    }
+
+    #[test]
+    fn absolute_keeps_absolute_inputs() {
+        let args = args(&[], Some(&base_dir()));
+        let input = std::env::temp_dir().join("elsewhere").join("a.pdf");
+        assert_eq!(absolute(&args, input.to_str().unwrap()), input);
+    }
+
+    #[test]
+    fn format_override_uses_the_most_specific_input() {
+        let base = base_dir();
+        let args = args(
+            &["scans:txt", "scans/invoices:csv", "other.pdf"],
+            Some(&base),
+        );
+        let scans = base.join("scans");
+        assert_eq!(
+            format_override(&args, &scans.join("invoices").join("a.pdf")),
+            Some(OutputFormat::Csv)
+        );
+        assert_eq!(
+            format_override(&args, &scans.join("b.pdf")),
+            Some(OutputFormat::Txt)
+        );
+        assert_eq!(format_override(&args, &base.join("other.pdf")), None);
+    }
+
+    #[test]
+    fn format_override_matches_whole_components() {
+        let base = base_dir();
+        let args = args(&["scans:txt"], Some(&base));
+        assert_eq!(
+            format_override(&args, &base.join("scans2").join("a.pdf")),
+            None
+        );
+    }
+
+    #[test]
+    fn has_format_overrides_looks_at_every_input() {
+        assert!(!has_format_overrides(&args(&["a.pdf", "scans"], None)));
+        assert!(has_format_overrides(&args(&["a.pdf", "scans:jsonl"], None)));
+    }
+}
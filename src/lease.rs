@@ -0,0 +1,117 @@
+use anyhow::{anyhow, Result};
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::{Duration, SystemTime};
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+use tokio::task::JoinHandle;
+use tokio::time::sleep;
+
+fn path(address: &str) -> PathBuf {
+    let name: String = address
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    std::env::temp_dir().join(format!("umi-http-{}.lease", name))
+}
+
+/// How long ago another process last renewed its lease on `address`, if it
+/// did so within `stale_after`.
+pub async fn held_elsewhere(address: &str, stale_after: Duration) -> Option<Duration> {
+    let path = path(address);
+    let owner = fs::read_to_string(&path).await.ok()?;
+    if owner.trim() == process::id().to_string() {
+        return None;
+    }
+    let modified = fs::metadata(&path).await.ok()?.modified().ok()?;
+    let age = SystemTime::now()
+        .duration_since(modified)
+        .unwrap_or_default();
+    (age < stale_after).then_some(age)
+}
+
+async fn create(path: &Path) -> io::Result<()> {
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)
+        .await?;
+    file.write_all(process::id().to_string().as_bytes()).await
+}
+
+/// Creates the lease file for `address`, replacing one that is stale or left
+/// by this process. Fails if another run holds a fresh lease.
+async fn take(address: &str, stale_after: Duration) -> Result<()> {
+    let path = path(address);
+    let mut result = create(&path).await;
+    if result
+        .as_ref()
+        .is_err_and(|error| error.kind() == ErrorKind::AlreadyExists)
+    {
+        if let Some(age) = held_elsewhere(address, stale_after).await {
+            return Err(anyhow!(
+                "Umi-OCR at {} is in use by another run (active {}s ago)",
+                address,
+                age.as_secs()
+            ));
+        }
+        remove(address).await;
+        result = create(&path).await;
+    }
+    result.map_err(|error| match error.kind() {
+        ErrorKind::AlreadyExists => anyhow!("Umi-OCR at {} is in use by another run", address),
+        _ => anyhow!("Error creating lease {}: {}", path.display(), error),
+    })
+}
+
+pub async fn remove(address: &str) {
+    let _ = fs::remove_file(path(address)).await;
+}
+
+/// Marker files renewed while this run owns the BatchDOC tabs of its servers,
+/// so that other runs can tell an active tab from one left by a crash.
+pub struct Lease {
+    addresses: Vec<String>,
+    task: JoinHandle<()>,
+}
+
+impl Lease {
+    /// Takes the leases on all of `addresses` before renewing them, giving
+    /// back the ones already taken if another run holds any of the rest.
+    pub async fn acquire(addresses: Vec<String>, stale_after: Duration) -> Result<Self> {
+        for (taken, address) in addresses.iter().enumerate() {
+            if let Err(error) = take(address, stale_after).await {
+                for address in &addresses[..taken] {
+                    remove(address).await;
+                }
+                return Err(error);
+            }
+        }
+        Ok(Self::hold(addresses, stale_after))
+    }
+
+    fn hold(addresses: Vec<String>, stale_after: Duration) -> Self {
+        let paths: Vec<PathBuf> = addresses.iter().map(|address| path(address)).collect();
+        // Well within `stale_after`, so a live lease never looks stale.
+        let renew = stale_after / 3;
+        let task = tokio::spawn(async move {
+            loop {
+                for path in &paths {
+                    if let Err(error) = fs::write(path, process::id().to_string()).await {
+                        println!("Could not renew lease {}: {}", path.display(), error);
+                    }
+                }
+                sleep(renew).await;
+            }
+        });
+        Self { addresses, task }
+    }
+
+    pub async fn release(self) {
+        self.task.abort();
+        for address in &self.addresses {
+            remove(address).await;
+        }
+    }
+}
@@ -0,0 +1,15 @@
+pub mod argv;
+mod config;
+mod inputs;
+mod lease;
+mod logs;
+mod metrics;
+pub mod paths;
+mod pdf;
+pub mod progress;
+mod report;
+mod run;
+pub mod server;
+
+pub use run::{execute, Args, Cancelled, Command, ExistingOutput};
+pub use tokio_util::sync::CancellationToken;
@@ -0,0 +1,172 @@
+use anyhow::{anyhow, Result};
+use std::collections::HashSet;
+use std::fmt::{self, Write as _};
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id};
+use tracing::{Event, Subscriber};
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::{LookupSpan, SpanRef};
+use tracing_subscriber::Layer;
+
+pub const DOCUMENT_SPAN: &str = "document";
+
+#[derive(Default)]
+struct Fields {
+    message: Option<String>,
+    path: Option<String>,
+    rest: String,
+}
+
+impl Visit for Fields {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        match field.name() {
+            "message" => self.message = Some(format!("{:?}", value)),
+            name => {
+                if name == "path" {
+                    self.path = Some(format!("{:?}", value));
+                }
+                let _ = write!(self.rest, " {}={:?}", name, value);
+            }
+        }
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        match field.name() {
+            "message" => self.message = Some(value.to_string()),
+            name => {
+                if name == "path" {
+                    self.path = Some(value.to_string());
+                }
+                let _ = write!(self.rest, " {}={}", name, value);
+            }
+        }
+    }
+}
+
+struct Started(Instant);
+
+#[derive(Clone)]
+struct DocumentLog(Arc<Mutex<File>>);
+
+impl DocumentLog {
+    fn write(&self, line: fmt::Arguments) {
+        let timestamp = humantime::format_rfc3339_millis(SystemTime::now());
+        let mut file = self.0.lock().unwrap();
+        let _ = writeln!(file, "{} {}", timestamp, line);
+    }
+}
+
+/// Writes everything that happens inside a document span to its own file in
+/// `dir`, named after the input.
+pub struct DocumentLogs {
+    dir: PathBuf,
+    names: Mutex<HashSet<String>>,
+}
+
+impl DocumentLogs {
+    pub fn new(dir: &Path) -> Result<Self> {
+        std::fs::create_dir_all(dir).map_err(|error| {
+            anyhow!("Error creating log directory {}: {}", dir.display(), error)
+        })?;
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            names: Mutex::new(HashSet::new()),
+        })
+    }
+
+    fn create(&self, source: &str) -> Option<DocumentLog> {
+        let stem = Path::new(source)
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_else(|| "document".to_string());
+        let mut names = self.names.lock().unwrap();
+        let mut name = format!("{}.log", stem);
+        let mut index = 1;
+        while names.contains(&name) {
+            index += 1;
+            name = format!("{}-{}.log", stem, index);
+        }
+        let path = self.dir.join(&name);
+        names.insert(name);
+        match File::create(&path) {
+            Ok(file) => Some(DocumentLog(Arc::new(Mutex::new(file)))),
+            Err(error) => {
+                eprintln!("Error creating log file {}: {}", path.display(), error);
+                None
+            }
+        }
+    }
+}
+
+fn document_log<'a, S>(span: &SpanRef<'a, S>) -> Option<DocumentLog>
+where
+    S: for<'lookup> LookupSpan<'lookup>,
+{
+    span.scope()
+        .find_map(|span| span.extensions().get::<DocumentLog>().cloned())
+}
+
+impl<S> Layer<S> for DocumentLogs
+where
+    S: Subscriber + for<'lookup> LookupSpan<'lookup>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut fields = Fields::default();
+        attrs.record(&mut fields);
+        let log = match (span.name(), &fields.path) {
+            (DOCUMENT_SPAN, Some(path)) => self.create(path),
+            _ => document_log(&span),
+        };
+        if let Some(log) = &log {
+            log.write(format_args!("{} started{}", span.name(), fields.rest));
+        }
+        let mut extensions = span.extensions_mut();
+        extensions.insert(Started(Instant::now()));
+        if span.name() == DOCUMENT_SPAN {
+            if let Some(log) = log {
+                extensions.insert(log);
+            }
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let Some(span) = ctx.event_span(event) else {
+            return;
+        };
+        let Some(log) = document_log(&span) else {
+            return;
+        };
+        let mut fields = Fields::default();
+        event.record(&mut fields);
+        log.write(format_args!(
+            "{} {} {}{}",
+            event.metadata().level(),
+            span.name(),
+            fields.message.unwrap_or_default(),
+            fields.rest
+        ));
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(&id) else {
+            return;
+        };
+        let Some(log) = document_log(&span) else {
+            return;
+        };
+        let elapsed = span
+            .extensions()
+            .get::<Started>()
+            .map(|started| started.0.elapsed())
+            .unwrap_or_default();
+        log.write(format_args!("{} finished in {:?}", span.name(), elapsed));
+    }
+}
@@ -0,0 +1,139 @@
+use anyhow::{anyhow, Result};
+use std::fmt::Write;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+
+#[derive(Default)]
+pub struct Metrics {
+    pub processed: AtomicU64,
+    pub succeeded: AtomicU64,
+    pub failed: AtomicU64,
+    pub skipped: AtomicU64,
+    pub pages: AtomicU64,
+    pub queue_depth: AtomicU64,
+}
+
+impl Metrics {
+    pub fn record(&self, success: bool, pages: Option<usize>) {
+        self.processed.fetch_add(1, Ordering::Relaxed);
+        if success {
+            self.succeeded.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.failed.fetch_add(1, Ordering::Relaxed);
+        }
+        if let Some(pages) = pages {
+            self.pages.fetch_add(pages as u64, Ordering::Relaxed);
+        }
+        self.dequeue();
+    }
+
+    pub fn skip(&self) {
+        self.skipped.fetch_add(1, Ordering::Relaxed);
+        self.dequeue();
+    }
+
+    fn dequeue(&self) {
+        let _ = self
+            .queue_depth
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |depth| {
+                depth.checked_sub(1)
+            });
+    }
+
+    fn render(&self) -> String {
+        let mut body = String::new();
+        for (name, kind, help, value) in [
+            (
+                "umi_http_documents_processed_total",
+                "counter",
+                "Documents that finished processing.",
+                &self.processed,
+            ),
+            (
+                "umi_http_documents_succeeded_total",
+                "counter",
+                "Documents that produced a valid output.",
+                &self.succeeded,
+            ),
+            (
+                "umi_http_documents_failed_total",
+                "counter",
+                "Documents that failed to produce a valid output.",
+                &self.failed,
+            ),
+            (
+                "umi_http_documents_skipped_total",
+                "counter",
+                "Documents skipped as their output already existed.",
+                &self.skipped,
+            ),
+            (
+                "umi_http_pages_processed_total",
+                "counter",
+                "Pages in the outputs produced so far.",
+                &self.pages,
+            ),
+            (
+                "umi_http_queue_depth",
+                "gauge",
+                "Documents still waiting to be processed.",
+                &self.queue_depth,
+            ),
+        ] {
+            let _ = writeln!(body, "# HELP {} {}", name, help);
+            let _ = writeln!(body, "# TYPE {} {}", name, kind);
+            let _ = writeln!(body, "{} {}", name, value.load(Ordering::Relaxed));
+        }
+        body
+    }
+}
+
+pub struct MetricsServer {
+    shutdown: oneshot::Sender<()>,
+    handle: JoinHandle<()>,
+}
+
+impl MetricsServer {
+    pub async fn start(address: IpAddr, port: u16, metrics: Arc<Metrics>) -> Result<Self> {
+        let address = SocketAddr::new(address, port);
+        let listener = TcpListener::bind(address)
+            .await
+            .map_err(|error| anyhow!("Error binding metrics address {}: {}", address, error))?;
+        println!("Serving metrics on {}.", address);
+        let (shutdown, mut stop) = oneshot::channel();
+        let handle = tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = &mut stop => break,
+                    accepted = listener.accept() => {
+                        let Ok((mut stream, _)) = accepted else { continue };
+                        let body = metrics.render();
+                        tokio::spawn(async move {
+                            let mut request = [0u8; 1024];
+                            let _ = stream.read(&mut request).await;
+                            let response = format!(
+                                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                                body.len(),
+                                body
+                            );
+                            let _ = stream.write_all(response.as_bytes()).await;
+                            let _ = stream.shutdown().await;
+                        });
+                    }
+                }
+            }
+        });
+        Ok(Self { shutdown, handle })
+    }
+
+    pub async fn shutdown(self) {
+        let _ = self.shutdown.send(());
+        let _ = self.handle.await;
+        println!("Metrics endpoint stopped.");
+    }
+}
@@ -0,0 +1,188 @@
+use std::path::{Path, PathBuf};
+
+pub const OUTPUT_SUFFIX: &str = ".layered.pdf";
+pub const TEXT_PDF_SUFFIX: &str = ".text.pdf";
+
+/// Export format of a BatchDOC output.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum OutputFormat {
+    /// PDF with a text layer (`<name>.layered.pdf`)
+    LayeredPdf,
+    /// PDF with only the recognized text (`<name>.text.pdf`)
+    TextPdf,
+    /// Plain text (`<name>.txt`)
+    Txt,
+    /// JSON lines (`<name>.jsonl`)
+    Jsonl,
+    /// CSV (`<name>.csv`)
+    Csv,
+}
+
+impl OutputFormat {
+    pub const ALL: [Self; 5] = [
+        Self::LayeredPdf,
+        Self::TextPdf,
+        Self::Txt,
+        Self::Jsonl,
+        Self::Csv,
+    ];
+
+    pub fn suffix(self) -> &'static str {
+        match self {
+            Self::LayeredPdf => OUTPUT_SUFFIX,
+            Self::TextPdf => TEXT_PDF_SUFFIX,
+            Self::Txt => ".txt",
+            Self::Jsonl => ".jsonl",
+            Self::Csv => ".csv",
+        }
+    }
+
+    /// BatchDOC setting that enables this format.
+    pub fn setting(self) -> &'static str {
+        match self {
+            Self::LayeredPdf => "mission.filesType.pdfLayered",
+            Self::TextPdf => "mission.filesType.pdfOneLayer",
+            Self::Txt => "mission.filesType.txt",
+            Self::Jsonl => "mission.filesType.jsonl",
+            Self::Csv => "mission.filesType.csv",
+        }
+    }
+}
+
+/// Kind of PDF BatchDOC exports.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PdfMode {
+    /// Page images with an invisible text layer
+    DoubleLayer,
+    /// Only the recognized text
+    TextOnly,
+}
+
+impl PdfMode {
+    pub fn format(self) -> OutputFormat {
+        match self {
+            Self::DoubleLayer => OutputFormat::LayeredPdf,
+            Self::TextOnly => OutputFormat::TextPdf,
+        }
+    }
+}
+
+#[cfg(windows)]
+const MAX_PATH: usize = 260;
+
+#[cfg(windows)]
+fn split_prefix(path: &str) -> (bool, &str) {
+    if let Some(rest) = path.strip_prefix(r"\\?\UNC\") {
+        (true, rest)
+    } else if let Some(rest) = path.strip_prefix(r"\\?\") {
+        (false, rest)
+    } else if let Some(rest) = path.strip_prefix(r"\\").or_else(|| path.strip_prefix("//")) {
+        (true, rest)
+    } else {
+        (false, path)
+    }
+}
+
+/// Formats `path` the way Umi-OCR expects it in `addDocs`.
+///
+/// Paths are sent with forward slashes, UNC shares as `//server/share/...`.
+/// Paths at or beyond `MAX_PATH` cannot use forward slashes, so they are sent
+/// in extended-length form (`\\?\C:\...` or `\\?\UNC\server\share\...`).
+#[cfg(windows)]
+pub fn umi_path(path: &Path) -> String {
+    let path = std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf());
+    let raw = path.to_string_lossy();
+    let (unc, rest) = split_prefix(&raw);
+    let rest = rest.replace('/', "\\");
+    let len = if unc { rest.len() + 2 } else { rest.len() };
+    match (unc, len >= MAX_PATH) {
+        (true, true) => format!(r"\\?\UNC\{}", rest),
+        (false, true) => format!(r"\\?\{}", rest),
+        (true, false) => format!("//{}", rest.replace('\\', "/")),
+        (false, false) => rest.replace('\\', "/"),
+    }
+}
+
+#[cfg(not(windows))]
+pub fn umi_path(path: &Path) -> String {
+    path.to_string_lossy().replace('\\', "/")
+}
+
+pub fn output_path(path: &Path) -> PathBuf {
+    output_path_as(path, OutputFormat::LayeredPdf)
+}
+
+pub fn output_path_as(path: &Path, format: OutputFormat) -> PathBuf {
+    let path_rm_ext = path.with_extension("");
+    let file_name = path_rm_ext.file_name().unwrap().to_string_lossy();
+    path.with_file_name(format!("{}{}", file_name, format.suffix()))
+}
+
+#[cfg(all(test, windows))]
+mod tests {
+    use super::*;
+
+    /// `C:\` followed by components making a path of exactly `len` characters.
+    fn long_path(len: usize) -> String {
+        let dir = "d".repeat(200);
+        format!(r"C:\{}\{}", dir, "f".repeat(len - dir.len() - 4))
+    }
+
+    #[test]
+    fn split_prefix_recognizes_unc_and_verbatim_paths() {
+        assert_eq!(
+            split_prefix(r"\\server\share\a.pdf"),
+            (true, r"server\share\a.pdf")
+        );
+        assert_eq!(
+            split_prefix("//server/share/a.pdf"),
+            (true, "server/share/a.pdf")
+        );
+        assert_eq!(
+            split_prefix(r"\\?\UNC\server\share\a.pdf"),
+            (true, r"server\share\a.pdf")
+        );
+        assert_eq!(split_prefix(r"\\?\C:\dir\a.pdf"), (false, r"C:\dir\a.pdf"));
+        assert_eq!(split_prefix(r"C:\dir\a.pdf"), (false, r"C:\dir\a.pdf"));
+    }
+
+    #[test]
+    fn umi_path_sends_short_paths_with_forward_slashes() {
+        assert_eq!(umi_path(Path::new(r"C:\dir\a.pdf")), "C:/dir/a.pdf");
+        assert_eq!(
+            umi_path(Path::new(r"\\server\share\dir\a.pdf")),
+            "//server/share/dir/a.pdf"
+        );
+        assert_eq!(
+            umi_path(Path::new(r"\\?\UNC\server\share\a.pdf")),
+            "//server/share/a.pdf"
+        );
+        assert_eq!(umi_path(Path::new(r"\\?\C:\dir\a.pdf")), "C:/dir/a.pdf");
+    }
+
+    #[test]
+    fn umi_path_sends_long_paths_in_extended_length_form() {
+        let short = long_path(MAX_PATH - 1);
+        assert_eq!(umi_path(Path::new(&short)), short.replace('\\', "/"));
+        let long = long_path(MAX_PATH);
+        assert_eq!(umi_path(Path::new(&long)), format!(r"\\?\{}", long));
+        let long = long_path(300);
+        assert_eq!(
+            umi_path(Path::new(&format!(r"\\?\{}", long))),
+            format!(r"\\?\{}", long)
+        );
+    }
+
+    #[test]
+    fn umi_path_sends_long_unc_paths_in_extended_length_form() {
+        let share = format!(r"server\share\{}\a.pdf", "d".repeat(MAX_PATH));
+        assert_eq!(
+            umi_path(Path::new(&format!(r"\\{}", share))),
+            format!(r"\\?\UNC\{}", share)
+        );
+        assert_eq!(
+            umi_path(Path::new(&format!(r"\\?\UNC\{}", share))),
+            format!(r"\\?\UNC\{}", share)
+        );
+    }
+}
@@ -0,0 +1,221 @@
+use anyhow::{anyhow, Result};
+use lopdf::{Dictionary, Document, Object, ObjectId, PdfMetadata};
+use std::fs;
+use std::path::{Path, PathBuf};
+use tokio::task;
+
+async fn metadata(path: &Path) -> Result<PdfMetadata> {
+    let path: PathBuf = path.to_path_buf();
+    task::spawn_blocking(move || {
+        Document::load_metadata(&path)
+            .map_err(|error| anyhow!("Error reading PDF at path {}: {}", path.display(), error))
+    })
+    .await?
+}
+
+pub async fn page_count(path: &Path) -> Result<usize> {
+    Ok(metadata(path).await?.page_count as usize)
+}
+
+pub async fn needs_password(path: &Path) -> Result<bool> {
+    let metadata = metadata(path).await?;
+    Ok(metadata.encrypted && metadata.page_count == 0)
+}
+
+/// Number of non-whitespace characters in the document's text layer.
+pub async fn text_length(path: &Path) -> Result<usize> {
+    let path: PathBuf = path.to_path_buf();
+    task::spawn_blocking(move || {
+        let error = |error: lopdf::Error| {
+            anyhow!(
+                "Error extracting text from PDF at path {}: {}",
+                path.display(),
+                error
+            )
+        };
+        let document = Document::load(&path).map_err(error)?;
+        let pages: Vec<u32> = document.get_pages().into_keys().collect();
+        let text = document.extract_text(&pages).map_err(error)?;
+        Ok(text.chars().filter(|c| !c.is_whitespace()).count())
+    })
+    .await?
+}
+
+/// Appends `keywords` to the document's Info dictionary, rewriting the file
+/// in place.
+pub async fn tag(path: &Path, keywords: &str) -> Result<()> {
+    let path: PathBuf = path.to_path_buf();
+    let keywords = keywords.to_string();
+    task::spawn_blocking(move || {
+        let error = |error: lopdf::Error| {
+            anyhow!("Error tagging PDF at path {}: {}", path.display(), error)
+        };
+        let mut document = Document::load(&path).map_err(error)?;
+        let info = document.trailer.get(b"Info").ok();
+        let id = match info.and_then(|info| info.as_reference().ok()) {
+            Some(id) => id,
+            None => {
+                let info = info
+                    .and_then(|info| info.as_dict().ok())
+                    .cloned()
+                    .unwrap_or_else(Dictionary::new);
+                let id = document.add_object(info);
+                document.trailer.set("Info", id);
+                id
+            }
+        };
+        let info = document
+            .get_object_mut(id)
+            .and_then(Object::as_dict_mut)
+            .map_err(error)?;
+        let keywords = match info.get(b"Keywords").and_then(Object::as_str) {
+            Ok(existing) if !existing.is_empty() => {
+                format!("{}; {}", String::from_utf8_lossy(existing), keywords)
+            }
+            _ => keywords,
+        };
+        info.set("Keywords", Object::string_literal(keywords));
+        let temp = path.with_extension("pdf.tmp");
+        document
+            .save(&temp)
+            .map_err(|error| anyhow!("Error writing PDF at path {}: {}", temp.display(), error))?;
+        fs::rename(&temp, &path)
+            .map_err(|error| anyhow!("Error replacing PDF at path {}: {}", path.display(), error))
+    })
+    .await?
+}
+
+const INHERITED: [&[u8]; 4] = [b"Resources", b"MediaBox", b"CropBox", b"Rotate"];
+
+/// Copies the attributes each page inherits from its page tree onto the page,
+/// so it can be moved under another parent, and returns the pages in order.
+fn detach_pages(document: &mut Document) -> lopdf::Result<Vec<ObjectId>> {
+    let pages: Vec<ObjectId> = document.page_iter().collect();
+    for &id in &pages {
+        let page = document.get_dictionary(id)?;
+        let mut missing: Vec<&[u8]> = INHERITED
+            .iter()
+            .copied()
+            .filter(|key| !page.has(key))
+            .collect();
+        let mut parent = page.get(b"Parent").and_then(Object::as_reference).ok();
+        let mut inherited = Vec::new();
+        while let Some(node) = parent.filter(|_| !missing.is_empty()) {
+            let node = document.get_dictionary(node)?;
+            missing.retain(|key| match node.get(key) {
+                Ok(value) => {
+                    inherited.push((key.to_vec(), value.clone()));
+                    false
+                }
+                Err(_) => true,
+            });
+            parent = node.get(b"Parent").and_then(Object::as_reference).ok();
+        }
+        let page = document.get_dictionary_mut(id)?;
+        for (key, value) in inherited {
+            page.set(key, value);
+        }
+    }
+    Ok(pages)
+}
+
+/// Makes `pages` the only children of the document's page tree root.
+fn set_pages(document: &mut Document, pages: &[ObjectId]) -> lopdf::Result<()> {
+    let root = document.catalog()?.get(b"Pages")?.as_reference()?;
+    for &id in pages {
+        document.get_dictionary_mut(id)?.set("Parent", root);
+    }
+    let tree = document.get_dictionary_mut(root)?;
+    tree.set(
+        "Kids",
+        pages
+            .iter()
+            .map(|&id| Object::Reference(id))
+            .collect::<Vec<_>>(),
+    );
+    tree.set("Count", pages.len() as i64);
+    Ok(())
+}
+
+/// Writes `path` to `dir` as consecutive parts of at most `max_pages` pages
+/// each, returning them in page order.
+pub async fn split(path: &Path, max_pages: usize, dir: &Path) -> Result<Vec<PathBuf>> {
+    let path: PathBuf = path.to_path_buf();
+    let dir: PathBuf = dir.to_path_buf();
+    task::spawn_blocking(move || {
+        let error = |error: lopdf::Error| {
+            anyhow!("Error splitting PDF at path {}: {}", path.display(), error)
+        };
+        let mut document = Document::load(&path).map_err(error)?;
+        let pages = detach_pages(&mut document).map_err(error)?;
+        let stem = path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_else(|| "document".to_string());
+        let mut parts = Vec::new();
+        for (index, chunk) in pages.chunks(max_pages.max(1)).enumerate() {
+            let mut part = document.clone();
+            set_pages(&mut part, chunk).map_err(error)?;
+            part.catalog_mut().map_err(error)?.remove(b"Outlines");
+            part.prune_objects();
+            let target = dir.join(format!("{}.part{:04}.pdf", stem, index + 1));
+            part.save(&target).map_err(|error| {
+                anyhow!("Error writing PDF at path {}: {}", target.display(), error)
+            })?;
+            parts.push(target);
+        }
+        Ok(parts)
+    })
+    .await?
+}
+
+/// Concatenates the pages of `parts`, in order, into a new PDF at `output`.
+pub async fn merge(parts: &[PathBuf], output: &Path) -> Result<()> {
+    let parts = parts.to_vec();
+    let output: PathBuf = output.to_path_buf();
+    task::spawn_blocking(move || {
+        let error = |error: lopdf::Error| {
+            anyhow!("Error merging PDF at path {}: {}", output.display(), error)
+        };
+        let mut merged: Option<Document> = None;
+        let mut pages = Vec::new();
+        for part in &parts {
+            let mut document = Document::load(part).map_err(|error| {
+                anyhow!("Error reading PDF at path {}: {}", part.display(), error)
+            })?;
+            match &mut merged {
+                None => {
+                    pages.extend(detach_pages(&mut document).map_err(error)?);
+                    merged = Some(document);
+                }
+                Some(merged) => {
+                    document.renumber_objects_with(merged.max_id + 1);
+                    pages.extend(detach_pages(&mut document).map_err(error)?);
+                    merged.max_id = document.max_id;
+                    merged.objects.extend(document.objects);
+                }
+            }
+        }
+        let mut merged = merged.ok_or_else(|| anyhow!("No PDFs to merge"))?;
+        if pages.is_empty() {
+            return Err(anyhow!(
+                "No pages found in the parts of {}",
+                output.display()
+            ));
+        }
+        set_pages(&mut merged, &pages).map_err(error)?;
+        merged.prune_objects();
+        let temp = output.with_extension("pdf.tmp");
+        merged
+            .save(&temp)
+            .map_err(|error| anyhow!("Error writing PDF at path {}: {}", temp.display(), error))?;
+        fs::rename(&temp, &output).map_err(|error| {
+            anyhow!(
+                "Error replacing PDF at path {}: {}",
+                output.display(),
+                error
+            )
+        })
+    })
+    .await?
+}
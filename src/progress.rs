@@ -0,0 +1,123 @@
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Step a document is in on its Umi-OCR server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    /// Closing leftover tabs and opening (or checking) the BatchDOC tab.
+    Tab,
+    /// Adding the document to the BatchDOC tab.
+    Add,
+    /// Starting processing.
+    Start,
+    /// Waiting for the output to appear and settle.
+    Watch,
+    /// Checking the output is complete.
+    Validate,
+}
+
+#[derive(Debug, Clone)]
+pub enum ProgressEvent {
+    /// A document was found and is waiting to be processed.
+    Queued { input: PathBuf },
+    /// A document moved on to another phase.
+    Phase {
+        input: PathBuf,
+        server: String,
+        phase: Phase,
+    },
+    /// A document produced a valid output.
+    Succeeded {
+        input: PathBuf,
+        output: PathBuf,
+        pages: Option<usize>,
+    },
+    /// A document was skipped as its output already exists.
+    Skipped { input: PathBuf },
+    /// A document failed.
+    Failed { input: PathBuf, error: String },
+    /// The estimated time left for the documents still queued changed.
+    ///
+    /// Inputs are discovered while earlier ones are processed, so `documents`
+    /// and `remaining` only cover the inputs found so far.
+    Estimate {
+        remaining: Duration,
+        documents: usize,
+    },
+    /// The run ended, successfully or not.
+    Finished { processed: usize, failed: usize },
+}
+
+type Callback = dyn FnMut(ProgressEvent) + Send;
+
+/// Callback invoked as a run advances. The default does nothing.
+#[derive(Clone, Default)]
+pub struct Progress(Option<Arc<Mutex<Callback>>>);
+
+impl Progress {
+    pub fn new(callback: impl FnMut(ProgressEvent) + Send + 'static) -> Self {
+        Self(Some(Arc::new(Mutex::new(callback))))
+    }
+
+    pub fn emit(&self, event: ProgressEvent) {
+        if let Some(callback) = &self.0 {
+            (callback.lock().unwrap())(event);
+        }
+    }
+}
+
+/// Smoothing factor applied to each new throughput sample.
+const SMOOTHING: f64 = 0.3;
+
+#[derive(Debug)]
+struct Throughput {
+    since: Instant,
+    pages: usize,
+    documents: usize,
+    rate: Option<f64>,
+}
+
+/// Running estimate of the time left in a run, from an exponential moving
+/// average of the pages processed per second.
+///
+/// Umi-OCR does not report progress within a document, so a sample is taken
+/// each time a document finishes, and documents still queued are assumed to
+/// have as many pages as the average finished one.
+#[derive(Debug)]
+pub struct Eta(Mutex<Throughput>);
+
+impl Default for Eta {
+    fn default() -> Self {
+        Self(Mutex::new(Throughput {
+            since: Instant::now(),
+            pages: 0,
+            documents: 0,
+            rate: None,
+        }))
+    }
+}
+
+impl Eta {
+    /// Records a finished document of `pages` pages and returns the time
+    /// `remaining` documents are estimated to take.
+    pub fn finished(&self, pages: usize, remaining: usize) -> Option<Duration> {
+        let mut throughput = self.0.lock().unwrap();
+        let now = Instant::now();
+        let elapsed = now.duration_since(throughput.since).as_secs_f64();
+        throughput.since = now;
+        throughput.pages += pages;
+        throughput.documents += 1;
+        if elapsed > 0.0 {
+            let sample = pages as f64 / elapsed;
+            throughput.rate = Some(match throughput.rate {
+                Some(rate) => rate + SMOOTHING * (sample - rate),
+                None => sample,
+            });
+        }
+        let rate = throughput.rate.filter(|rate| *rate > 0.0)?;
+        let average = throughput.pages as f64 / throughput.documents as f64;
+        Some(Duration::from_secs_f64(remaining as f64 * average / rate))
+    }
+}
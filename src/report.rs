@@ -0,0 +1,225 @@
+use anyhow::{anyhow, Result};
+use sha2::{Digest, Sha256};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+const HEADER: &[&str] = &[
+    "timestamp",
+    "input",
+    "output",
+    "status",
+    "pages",
+    "elapsed",
+    "error",
+];
+
+pub struct Row<'a> {
+    pub input: &'a Path,
+    pub output: Option<&'a Path>,
+    pub pages: Option<usize>,
+    pub elapsed: Duration,
+    pub error: Option<&'a anyhow::Error>,
+    pub skipped: bool,
+}
+
+fn field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+fn line(fields: &[String]) -> String {
+    let fields: Vec<String> = fields.iter().map(|value| field(value)).collect();
+    format!("{}\n", fields.join(","))
+}
+
+/// Splits CSV text as written by [`CsvReport`] into records of fields.
+fn records(text: &str) -> Vec<Vec<String>> {
+    let mut records = Vec::new();
+    let mut record = Vec::new();
+    let mut value = String::new();
+    let mut quoted = false;
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, quoted) {
+            ('"', true) if chars.peek() == Some(&'"') => {
+                chars.next();
+                value.push('"');
+            }
+            ('"', _) => quoted = !quoted,
+            (',', false) => record.push(std::mem::take(&mut value)),
+            ('\n', false) => {
+                record.push(std::mem::take(&mut value));
+                records.push(std::mem::take(&mut record));
+            }
+            ('\r', false) => {}
+            _ => value.push(c),
+        }
+    }
+    if !value.is_empty() || !record.is_empty() {
+        record.push(value);
+        records.push(record);
+    }
+    records
+}
+
+/// Inputs whose latest row in the report at `path` marks them as failed.
+pub fn failed_inputs(path: &Path) -> Result<Vec<PathBuf>> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|error| anyhow!("Error reading report {}: {}", path.display(), error))?;
+    let mut records = records(&text).into_iter();
+    let header = records.next().unwrap_or_default();
+    let column = |name: &str| {
+        header
+            .iter()
+            .position(|field| field == name)
+            .ok_or_else(|| anyhow!("Report {} has no {} column", path.display(), name))
+    };
+    let (input, status) = (column("input")?, column("status")?);
+    let mut inputs: Vec<(String, bool)> = Vec::new();
+    for record in records {
+        let (Some(path), Some(status)) = (record.get(input), record.get(status)) else {
+            continue;
+        };
+        inputs.retain(|(input, _)| input != path);
+        inputs.push((path.clone(), status == "failed"));
+    }
+    Ok(inputs
+        .into_iter()
+        .filter(|(_, failed)| *failed)
+        .map(|(input, _)| PathBuf::from(input))
+        .collect())
+}
+
+/// CSV file that gets one row appended per processed document.
+///
+/// Each row is written with a single append so rows from concurrent runs
+/// sharing the file do not interleave.
+pub struct CsvReport(Mutex<File>);
+
+impl CsvReport {
+    pub fn open(path: &Path) -> Result<Self> {
+        let error = |error| anyhow!("Error opening report {}: {}", path.display(), error);
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(error)?;
+        if file.metadata().map_err(error)?.len() == 0 {
+            let header: Vec<String> = HEADER.iter().map(ToString::to_string).collect();
+            file.write_all(line(&header).as_bytes()).map_err(error)?;
+        }
+        Ok(Self(Mutex::new(file)))
+    }
+
+    pub fn record(&self, row: Row) {
+        let fields = [
+            humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+            row.input.display().to_string(),
+            row.output
+                .map(|output| output.display().to_string())
+                .unwrap_or_default(),
+            if row.skipped {
+                "skipped"
+            } else if row.error.is_none() {
+                "succeeded"
+            } else {
+                "failed"
+            }
+            .to_string(),
+            row.pages.map(|pages| pages.to_string()).unwrap_or_default(),
+            format!("{:.3}", row.elapsed.as_secs_f64()),
+            row.error.map(ToString::to_string).unwrap_or_default(),
+        ];
+        let mut file = self.0.lock().unwrap();
+        if let Err(error) = file.write_all(line(&fields).as_bytes()) {
+            println!("Could not write report row: {}", error);
+        }
+    }
+}
+
+/// File that gets one `<sha256>\t<input>\t<output>` line appended per
+/// produced output.
+pub struct HashLog {
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+async fn sha256(path: &Path) -> std::io::Result<String> {
+    let bytes = tokio::fs::read(path).await?;
+    Ok(Sha256::digest(&bytes)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect())
+}
+
+impl HashLog {
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|error| anyhow!("Error opening hash log {}: {}", path.display(), error))?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            file: Mutex::new(file),
+        })
+    }
+
+    pub async fn record(&self, input: &Path, output: &Path) {
+        let hash = match sha256(output).await {
+            Ok(hash) => hash,
+            Err(error) => {
+                println!("Could not hash {}: {}", output.display(), error);
+                return;
+            }
+        };
+        let line = format!("{}\t{}\t{}\n", hash, input.display(), output.display());
+        let mut file = self.file.lock().unwrap();
+        if let Err(error) = file.write_all(line.as_bytes()) {
+            println!("Could not write hash of {}: {}", output.display(), error);
+        }
+    }
+
+    /// Whether `output` still has the hash last recorded for it from
+    /// `input`, or `None` if none was recorded.
+    pub async fn matches(&self, input: &Path, output: &Path) -> Option<bool> {
+        let text = tokio::fs::read_to_string(&self.path).await.ok()?;
+        let (input, output_name) = (input.display().to_string(), output.display().to_string());
+        let recorded = text.lines().rev().find_map(|line| {
+            let mut fields = line.splitn(3, '\t');
+            let hash = fields.next()?;
+            (fields.next()? == input && fields.next()? == output_name).then_some(hash)
+        })?;
+        Some(sha256(output).await.is_ok_and(|hash| hash == recorded))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn hash_log_matches_the_last_recorded_hash() {
+        let dir = std::env::temp_dir().join(format!("umi-http-hashes-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let (input, output) = (dir.join("a.pdf"), dir.join("a.layered.pdf"));
+        let log = HashLog::open(&dir.join("hashes.tsv")).unwrap();
+        assert_eq!(log.matches(&input, &output).await, None);
+
+        std::fs::write(&output, b"first").unwrap();
+        log.record(&input, &output).await;
+        std::fs::write(&output, b"second").unwrap();
+        assert_eq!(log.matches(&input, &output).await, Some(false));
+        log.record(&input, &output).await;
+        assert_eq!(log.matches(&input, &output).await, Some(true));
+        assert_eq!(log.matches(&dir.join("b.pdf"), &output).await, None);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
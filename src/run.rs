@@ -0,0 +1,510 @@
+mod checks;
+mod command;
+mod dispatch;
+mod document;
+mod files;
+mod limits;
+mod output;
+mod tab;
+
+use self::dispatch::{
+    addresses, close_healthy_tabs, run_batch_all_servers, servers, worker, CancelFile, Dispatch,
+};
+use self::document::{print_disposed, Recorder};
+use self::output::output_format;
+use crate::config::{ConfigArgs, RunConfig};
+use crate::inputs::{self, InputArgs};
+use crate::lease::Lease;
+use crate::logs::DocumentLogs;
+use crate::metrics::{Metrics, MetricsServer};
+use crate::paths::{OutputFormat, PdfMode};
+use crate::progress::{Eta, Progress, ProgressEvent};
+use crate::report::{CsvReport, HashLog};
+use crate::server::{ClientArgs, Server};
+use anyhow::{anyhow, Result};
+use clap::builder::PossibleValuesParser;
+use clap::FromArgMatches;
+use futures::future::join_all;
+use std::collections::{BTreeSet, VecDeque};
+use std::ffi::{OsStr, OsString};
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Mutex as AsyncMutex;
+use tokio_util::sync::CancellationToken;
+use tracing::instrument;
+use tracing_subscriber::filter::{LevelFilter, Targets};
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::prelude::*;
+
+pub use command::Command;
+pub use limits::Cancelled;
+
+const HOST: &str = "127.0.0.1";
+const PORT: u16 = 1224;
+const TAB_NAME: &str = "BatchDOC";
+const DELAY: Duration = Duration::from_secs(1);
+const PAGE_TYPE: u16 = 3;
+const OUTPUT_EXTENSIONS: &[&str] = &["pdf", "txt", "jsonl", "csv"];
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct Args {
+    #[command(flatten)]
+    inputs: InputArgs,
+    #[command(flatten)]
+    limits: ConfigArgs,
+    /// Delete the input document once its output has been validated
+    #[arg(long, conflicts_with = "move_source")]
+    delete_source: bool,
+    /// Move the input document into this directory once its output has been
+    /// validated, numbering names that are already taken
+    #[arg(long, value_name = "DIR")]
+    move_source: Option<PathBuf>,
+    /// Move each validated output into this directory, numbering names that
+    /// are already taken
+    #[arg(long, value_name = "DIR")]
+    flatten_output: Option<PathBuf>,
+    /// Serve Prometheus metrics on this port while the run is in progress
+    #[arg(long, value_name = "PORT")]
+    metrics_port: Option<u16>,
+    /// Address the metrics endpoint listens on; use `0.0.0.0` to allow
+    /// scraping from other hosts
+    #[arg(long, value_name = "ADDRESS", default_value = HOST, requires = "metrics_port")]
+    metrics_address: IpAddr,
+    /// Stop after the current document once this file appears
+    #[arg(long, value_name = "PATH", conflicts_with = "batch_all")]
+    cancel_file: Option<PathBuf>,
+    /// Module template index passed to `--add_page` (see Umi-OCR `--all_modules`)
+    ///
+    /// Umi-OCR always appends the new tab after the existing ones; use
+    /// `--reuse-page` to target a specific slot instead.
+    #[arg(long, value_name = "INDEX", default_value_t = PAGE_TYPE)]
+    page_type: u16,
+    /// Reuse the BatchDOC tab at this page index (see Umi-OCR `--all_pages`)
+    ///
+    /// Skips closing the existing BatchDOC tabs and adding a new one.
+    #[arg(long, value_name = "INDEX", conflicts_with = "page_type")]
+    reuse_page: Option<u16>,
+    /// Queue every input into a single BatchDOC tab and start them together
+    ///
+    /// The tab exports every document in the same format, so all inputs must
+    /// resolve to one output format.
+    #[arg(long)]
+    batch_all: bool,
+    /// Treat the first new file in the input's directory as the output
+    /// instead of computing its expected name
+    #[arg(long, conflicts_with_all = ["batch_all", "servers"])]
+    auto_output: bool,
+    /// Umi-OCR host
+    #[arg(long, default_value = HOST)]
+    host: String,
+    /// Umi-OCR HTTP port
+    #[arg(long, default_value_t = PORT)]
+    port: u16,
+    /// Distribute documents across these Umi-OCR servers instead of `--host`/`--port`
+    ///
+    /// Each server gets its own BatchDOC tab and takes the next document as
+    /// soon as it is free. A server that becomes unreachable stops receiving
+    /// documents and its current document is handed to another server.
+    #[arg(
+        long,
+        value_name = "HOST:PORT",
+        value_delimiter = ',',
+        conflicts_with_all = ["host", "port"]
+    )]
+    servers: Vec<String>,
+    #[command(flatten)]
+    client: ClientArgs,
+    /// Write tracing spans for each document and phase to stderr as JSON lines
+    #[arg(long, env = "UMI_HTTP_TRACE_JSON")]
+    trace_json: bool,
+    /// Write a detailed log of each document (requests, responses, phase
+    /// timings) to its own file in this directory
+    #[arg(long, value_name = "DIR", env = "UMI_HTTP_LOG_DIR")]
+    log_dir: Option<PathBuf>,
+    /// Add a Keywords entry to each output PDF noting it was OCR'd by this
+    /// tool, with the version and time
+    #[arg(long)]
+    tag_output: bool,
+    /// Shell command run on each output once it is complete; `{output}` is
+    /// replaced with the quoted output path and a non-zero exit marks it as
+    /// failed
+    #[arg(long, value_name = "COMMAND")]
+    success_check: Option<String>,
+    /// Mark PDF outputs whose text layer has fewer than this many
+    /// non-whitespace characters as failed, as the OCR likely found no text
+    #[arg(long, value_name = "N")]
+    min_text_length: Option<usize>,
+    /// CSV file to append one row per processed document to
+    #[arg(long, value_name = "PATH", env = "UMI_HTTP_REPORT_CSV")]
+    report_csv: Option<PathBuf>,
+    /// File to append the SHA-256 of each output to, with its input
+    ///
+    /// With `--existing-output skip`, an existing output that no longer has
+    /// the hash last recorded for it is produced again.
+    #[arg(long, value_name = "PATH")]
+    record_hashes: Option<PathBuf>,
+    /// While a batch is processing, print how many documents are left in the
+    /// BatchDOC queue every this many seconds
+    #[arg(long, value_name = "SECONDS", requires = "batch_all")]
+    queue_interval: Option<u64>,
+    /// Refuse to start while another run started with this option has been
+    /// active on a server within this many seconds; older tabs are treated
+    /// as left by a crashed run and closed
+    ///
+    /// Runs are tracked with a file in this machine's temporary directory,
+    /// named after the server exactly as given, so runs on other hosts or in
+    /// other containers, or naming the server differently (`localhost` and
+    /// `127.0.0.1`), do not see each other. Runs without this option ignore
+    /// it and close the tabs anyway.
+    #[arg(long, value_name = "SECONDS", value_parser = clap::value_parser!(u64).range(1..), conflicts_with = "reuse_page")]
+    stale_after: Option<u64>,
+    /// Export format to select in the BatchDOC tab before starting. Inputs
+    /// given as `path:FORMAT` override it; with neither, the tab's current
+    /// setting is kept and a layered PDF is expected
+    #[arg(long, value_name = "FORMAT")]
+    output_format: Option<OutputFormat>,
+    /// Kind of PDF to export wherever a PDF output is expected and no
+    /// `path:FORMAT` was given, selected in the BatchDOC tab before starting.
+    /// Without it, the tab's current setting is kept and a double-layer PDF
+    /// is expected
+    #[arg(long, value_name = "MODE")]
+    pdf_mode: Option<PdfMode>,
+    /// What to do when an input's output already exists: let Umi-OCR
+    /// overwrite it, skip the input, fail, or keep it and move the new output
+    /// to a numbered name. Has no effect with --auto-output
+    #[arg(long, value_name = "POLICY", value_enum, default_value_t = ExistingOutput::Overwrite)]
+    existing_output: ExistingOutput,
+    /// Split PDFs longer than this many pages into parts that are processed
+    /// one after another, then merge their outputs into one
+    #[arg(long, value_name = "PAGES", conflicts_with_all = ["batch_all", "auto_output"])]
+    max_pages: Option<usize>,
+    /// While waiting for an output, check every this many seconds that
+    /// Umi-OCR is still reachable and fail as soon as it refuses connections
+    #[arg(long, value_name = "SECONDS")]
+    heartbeat: Option<u64>,
+    /// Document type to have Umi-OCR treat inputs as, whatever their
+    /// extension. Inputs with another extension are passed under a
+    /// temporary name ending in it
+    #[arg(long, value_name = "TYPE", value_parser = PossibleValuesParser::new(inputs::SUPPORTED_EXTENSIONS), conflicts_with = "batch_all")]
+    input_type: Option<String>,
+}
+
+impl Args {
+    /// Parses `flags` as they would be given on the command line, without
+    /// the program name, e.g. `["--path", "scans", "--recursive"]`.
+    pub fn from_flags<I, T>(flags: I) -> Result<Self>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<OsString> + Clone,
+    {
+        let command = <Self as clap::Args>::augment_args(
+            clap::Command::new(env!("CARGO_PKG_NAME")).no_binary_name(true),
+        );
+        let matches = command.try_get_matches_from(flags)?;
+        Ok(Self::from_arg_matches(&matches)?)
+    }
+
+    /// Arguments that process `paths` with every other option at its default.
+    pub fn new(paths: impl IntoIterator<Item = impl AsRef<OsStr>>) -> Result<Self> {
+        Self::from_flags(paths.into_iter().map(|path| {
+            let mut flag = OsString::from("--path=");
+            flag.push(path);
+            flag
+        }))
+    }
+
+    pub fn recursive(mut self, recursive: bool) -> Self {
+        self.inputs.recursive = recursive;
+        self
+    }
+
+    pub fn base_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.inputs.base_dir = Some(dir.into());
+        self
+    }
+
+    pub fn host(mut self, host: impl Into<String>) -> Self {
+        self.host = host.into();
+        self
+    }
+
+    pub fn port(mut self, port: u16) -> Self {
+        self.port = port;
+        self
+    }
+
+    pub fn output_format(mut self, format: OutputFormat) -> Self {
+        self.output_format = Some(format);
+        self
+    }
+
+    pub fn pdf_mode(mut self, mode: PdfMode) -> Self {
+        self.pdf_mode = Some(mode);
+        self
+    }
+
+    pub fn existing_output(mut self, policy: ExistingOutput) -> Self {
+        self.existing_output = policy;
+        self
+    }
+
+    pub fn report_csv(mut self, path: impl Into<PathBuf>) -> Self {
+        self.report_csv = Some(path.into());
+        self
+    }
+
+    pub fn success_check(mut self, command: impl Into<String>) -> Self {
+        self.success_check = Some(command.into());
+        self
+    }
+
+    pub fn min_text_length(mut self, length: usize) -> Self {
+        self.min_text_length = Some(length);
+        self
+    }
+}
+
+/// What to do when an input's output already exists.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExistingOutput {
+    Overwrite,
+    Skip,
+    Fail,
+    Rename,
+}
+
+#[instrument(skip_all, err(Display))]
+async fn run(args: &Args, config: &RunConfig, recorder: &Recorder<'_>) -> Result<()> {
+    let servers = servers(args)?;
+    if args.batch_all {
+        let inputs = inputs::resolve(&args.inputs).await?;
+        let formats: BTreeSet<OutputFormat> = inputs
+            .iter()
+            .map(|input| output_format(args, input).unwrap_or(OutputFormat::LayeredPdf))
+            .collect();
+        if formats.len() > 1 {
+            return Err(anyhow!(
+                "Inputs resolve to {} output formats, but --batch-all exports every input in the same one; give them the same `:FORMAT` or run without --batch-all",
+                formats.len()
+            ));
+        }
+        recorder
+            .metrics
+            .queue_depth
+            .store(inputs.len() as u64, Ordering::Relaxed);
+        for input in &inputs {
+            recorder.progress.emit(ProgressEvent::Queued {
+                input: input.clone(),
+            });
+        }
+        let result = run_batch_all_servers(args, config, &servers, &inputs, recorder).await;
+        if config.cancel.is_cancelled() {
+            close_healthy_tabs(args, config, &servers).await?;
+            return Err(Cancelled.into());
+        }
+        return result;
+    }
+
+    let metrics = recorder.metrics.clone();
+    let progress = recorder.progress.clone();
+    let stream = inputs::stream(&args.inputs, move |input| {
+        metrics.queue_depth.fetch_add(1, Ordering::Relaxed);
+        progress.emit(ProgressEvent::Queued {
+            input: input.to_path_buf(),
+        });
+    })?;
+    let dispatch = Dispatch {
+        requeued: Mutex::new(VecDeque::new()),
+        inputs: AsyncMutex::new(stream),
+        exhausted: AtomicBool::new(false),
+        cancelled: args.cancel_file.clone().map(CancelFile::watch),
+        interrupted: config.cancel.clone(),
+        stopped: AtomicBool::new(false),
+    };
+    let mut processed = 0;
+    let mut failed = 0;
+    let mut disposed = Vec::new();
+    let mut error = None;
+    while dispatch.has_remaining() && !dispatch.is_halted() {
+        let healthy: Vec<&Server> = servers
+            .iter()
+            .filter(|server| server.is_healthy())
+            .collect();
+        if healthy.is_empty() {
+            break;
+        }
+        let reports = join_all(
+            healthy
+                .into_iter()
+                .map(|server| worker(args, config, &servers, server, &dispatch, recorder)),
+        )
+        .await;
+        for report in reports {
+            processed += report.processed;
+            failed += report.failed;
+            disposed.extend(report.disposed);
+            if error.is_none() {
+                error = report.error;
+            }
+        }
+    }
+
+    if let Some(error) = error {
+        print_disposed(&disposed);
+        return Err(error);
+    }
+    let interrupted = config.cancel.is_cancelled();
+    let cancelled = dispatch.is_cancelled() || interrupted;
+    let mut skipped = dispatch.requeued.into_inner().unwrap().len();
+    let mut stream = dispatch.inputs.into_inner();
+    while stream.next().await.is_some() {
+        skipped += 1;
+    }
+    let total = stream.finish().await?;
+    if total > 1 {
+        print_disposed(&disposed);
+    }
+    if skipped > 0 && !cancelled {
+        return Err(anyhow!(
+            "No reachable Umi-OCR server left; {} of {} documents were not processed",
+            skipped,
+            total
+        ));
+    }
+    if cancelled {
+        close_healthy_tabs(args, config, &servers).await?;
+        println!(
+            "Cancelled: {} of {} documents processed, {} skipped.",
+            processed, total, skipped
+        );
+    }
+    if interrupted {
+        return Err(Cancelled.into());
+    }
+    if failed > 0 {
+        return Err(anyhow!(
+            "{} of {} documents did not produce a valid output",
+            failed,
+            total
+        ));
+    }
+    Ok(())
+}
+
+/// Runs the OCR job described by `args`, reporting its progress to
+/// `progress`.
+///
+/// Triggering `cancel` stops the run at the next request or while it waits,
+/// closes the BatchDOC tabs it opened and returns [`Cancelled`].
+pub async fn execute(args: &Args, progress: Progress, cancel: CancellationToken) -> Result<()> {
+    let json = args.trace_json.then(|| {
+        tracing_subscriber::fmt::layer()
+            .json()
+            .with_span_events(FmtSpan::CLOSE)
+            .with_writer(std::io::stderr)
+            .with_filter(LevelFilter::INFO)
+    });
+    let logs =
+        match &args.log_dir {
+            Some(dir) => Some(DocumentLogs::new(dir)?.with_filter(
+                Targets::new().with_target(env!("CARGO_CRATE_NAME"), LevelFilter::DEBUG),
+            )),
+            None => None,
+        };
+    if json.is_some() || logs.is_some() {
+        let _ = tracing_subscriber::registry()
+            .with(json)
+            .with(logs)
+            .try_init();
+    }
+    let config = RunConfig {
+        cancel,
+        ..RunConfig::from_args(&args.limits).await?
+    };
+    let metrics = Arc::new(Metrics::default());
+    let server = match args.metrics_port {
+        Some(port) => {
+            Some(MetricsServer::start(args.metrics_address, port, metrics.clone()).await?)
+        }
+        None => None,
+    };
+    let csv = args
+        .report_csv
+        .as_deref()
+        .map(CsvReport::open)
+        .transpose()?;
+    let hashes = args
+        .record_hashes
+        .as_deref()
+        .map(HashLog::open)
+        .transpose()?;
+    let recorder = Recorder {
+        metrics: &metrics,
+        csv: csv.as_ref(),
+        hashes: hashes.as_ref(),
+        progress: &progress,
+        eta: &Eta::default(),
+    };
+    let lease = match args.stale_after.map(Duration::from_secs) {
+        Some(stale_after) => Some(Lease::acquire(addresses(args), stale_after).await?),
+        None => None,
+    };
+    let result = run(args, &config, &recorder).await;
+    if let Some(lease) = lease {
+        lease.release().await;
+    }
+    progress.emit(ProgressEvent::Finished {
+        processed: metrics.succeeded.load(Ordering::Relaxed) as usize,
+        failed: metrics.failed.load(Ordering::Relaxed) as usize,
+    });
+    if let Some(server) = server {
+        server.shutdown().await;
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn new_uses_the_command_line_defaults() {
+        let args = Args::new(["scans"]).unwrap();
+        assert_eq!(args.inputs.path, ["scans"]);
+        assert_eq!(args.host, HOST);
+        assert_eq!(args.port, PORT);
+        assert_eq!(args.page_type, PAGE_TYPE);
+        assert_eq!(args.existing_output, ExistingOutput::Overwrite);
+        assert!(args.output_format.is_none());
+    }
+
+    #[test]
+    fn new_requires_a_path() {
+        assert!(Args::new(Vec::<&str>::new()).is_err());
+    }
+
+    #[test]
+    fn setters_override_the_defaults() {
+        let args = Args::new(["a.pdf"])
+            .unwrap()
+            .recursive(true)
+            .port(1300)
+            .output_format(OutputFormat::Txt)
+            .existing_output(ExistingOutput::Skip)
+            .report_csv("report.csv");
+        assert!(args.inputs.recursive);
+        assert_eq!(args.port, 1300);
+        assert_eq!(args.output_format, Some(OutputFormat::Txt));
+        assert_eq!(args.existing_output, ExistingOutput::Skip);
+        assert_eq!(args.report_csv, Some(PathBuf::from("report.csv")));
+    }
+
+    #[test]
+    fn from_flags_rejects_conflicting_options() {
+        let flags = ["--path", "a.pdf", "--batch-all", "--auto-output"];
+        assert!(Args::from_flags(flags).is_err());
+    }
+}
@@ -0,0 +1,113 @@
+use super::Args;
+use crate::pdf;
+use anyhow::{anyhow, Result};
+use std::path::Path;
+use std::process;
+use std::time::SystemTime;
+
+/// Runs `check` through `cmd` with the output path in the
+/// `UMI_HTTP_OUTPUT` environment variable, which `{output}` expands to
+/// quoted, so it needs no quoting.
+#[cfg(windows)]
+fn shell(check: &str, output: &Path) -> process::Command {
+    use std::os::windows::process::CommandExt;
+    let mut shell = process::Command::new("cmd");
+    shell
+        .arg("/C")
+        .raw_arg(check.replace("{output}", "\"%UMI_HTTP_OUTPUT%\""))
+        .env("UMI_HTTP_OUTPUT", output);
+    shell
+}
+
+/// Runs `check` through the shell with the output path passed as an
+/// argument, so `{output}` needs no quoting.
+#[cfg(not(windows))]
+fn shell(check: &str, output: &Path) -> process::Command {
+    let mut shell = process::Command::new("sh");
+    shell
+        .arg("-c")
+        .arg(check.replace("{output}", "\"$1\""))
+        .arg("sh")
+        .arg(output);
+    shell
+}
+
+async fn check_text(args: &Args, output: &Path) -> Result<()> {
+    let Some(min_text_length) = args.min_text_length else {
+        return Ok(());
+    };
+    if !output
+        .extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("pdf"))
+    {
+        return Ok(());
+    }
+    let length = pdf::text_length(output).await?;
+    println!(
+        "Extracted {} characters of text from {}.",
+        length,
+        output.display()
+    );
+    if length < min_text_length {
+        return Err(anyhow!(
+            "Output {} has {} characters of text, fewer than the minimum of {}",
+            output.display(),
+            length,
+            min_text_length
+        ));
+    }
+    Ok(())
+}
+
+pub(super) async fn check_success(args: &Args, output: &Path) -> Result<()> {
+    check_text(args, output).await?;
+    let Some(check) = &args.success_check else {
+        return Ok(());
+    };
+    let command = check.replace("{output}", &output.display().to_string());
+    println!("Running success check: {}", command);
+    let result = tokio::process::Command::from(shell(check, output))
+        .output()
+        .await
+        .map_err(|error| anyhow!("Error running success check {}: {}", command, error))?;
+    let captured = format!(
+        "{}{}",
+        String::from_utf8_lossy(&result.stdout),
+        String::from_utf8_lossy(&result.stderr)
+    );
+    let captured = captured.trim();
+    tracing::info!(%command, status = %result.status, output = captured, "success check");
+    if !result.status.success() {
+        return Err(anyhow!(
+            "Success check failed for {} ({}): {}",
+            output.display(),
+            result.status,
+            captured
+        ));
+    }
+    println!("Success check passed for {}.", output.display());
+    if !captured.is_empty() {
+        println!("{}", captured);
+    }
+    Ok(())
+}
+
+pub(super) async fn tag_output(args: &Args, output: &Path) {
+    if !args.tag_output
+        || !output
+            .extension()
+            .is_some_and(|extension| extension.eq_ignore_ascii_case("pdf"))
+    {
+        return;
+    }
+    let keywords = format!(
+        "OCR by {} {} at {}",
+        env!("CARGO_PKG_NAME"),
+        env!("CARGO_PKG_VERSION"),
+        humantime::format_rfc3339_seconds(SystemTime::now())
+    );
+    match pdf::tag(output, &keywords).await {
+        Ok(()) => println!("Tagged output at path: {}", output.display()),
+        Err(error) => println!("Could not tag output: {}", error),
+    }
+}
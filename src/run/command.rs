@@ -0,0 +1,302 @@
+use super::limits::{cancellable, with_limits, with_timeout};
+use super::output::{modified, validate_output, validate_settled, watch_output};
+use super::tab::{close_batch_tabs, tabs};
+use super::{execute, Args, HOST, PORT, TAB_NAME};
+use crate::argv::{ArgvCommand, KNOWN_COMMANDS};
+use crate::config::{ConfigFileArgs, RunConfig, WatchConfigArgs};
+use crate::inputs::{self, InputArgs};
+use crate::progress::Progress;
+use crate::server::{ClientArgs, Server};
+use crate::{lease, report};
+use anyhow::{anyhow, Result};
+use clap::Subcommand;
+use serde_json::json;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::time::{sleep, Instant};
+use tokio_util::sync::CancellationToken;
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Check that the inputs exist, are readable, supported and unencrypted
+    /// without contacting Umi-OCR
+    Validate(InputArgs),
+    /// Wait for and validate an output produced by a job started elsewhere
+    Watch(WatchArgs),
+    /// List the `/argv` commands the Umi-OCR server supports
+    Commands(CommandsArgs),
+    /// Close every BatchDOC tab left open on the Umi-OCR server
+    Clean(CleanArgs),
+    /// Wait until the Umi-OCR server responds, failing after a timeout
+    WaitReady(WaitReadyArgs),
+    /// Process again the documents a CSV report lists as failed, with the
+    /// options of a normal run, and append the outcomes to the report
+    ///
+    /// The report does not record the options of the earlier run, so pass
+    /// the ones it used again, e.g. `--output-format` or `--servers`.
+    #[command(mut_arg("path", |arg| arg.required(false)))]
+    Retry(Box<RetryArgs>),
+}
+
+#[derive(clap::Args, Debug)]
+pub struct ServerArgs {
+    /// Umi-OCR host
+    #[arg(long, default_value = HOST)]
+    host: String,
+    /// Umi-OCR port
+    #[arg(long, default_value_t = PORT)]
+    port: u16,
+    #[command(flatten)]
+    client: ClientArgs,
+}
+
+impl ServerArgs {
+    fn server(&self) -> Result<Server> {
+        Server::new(&format!("{}:{}", self.host, self.port), &self.client)
+    }
+}
+
+#[derive(clap::Args, Debug)]
+pub struct CleanArgs {
+    #[command(flatten)]
+    server: ServerArgs,
+    /// Only close the tabs if no run started with `--stale-after` has been
+    /// active on the server within this many seconds. Only runs on this
+    /// machine that named the server the same way are seen
+    #[arg(long, value_name = "SECONDS")]
+    stale_after: Option<u64>,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct WaitReadyArgs {
+    #[command(flatten)]
+    server: ServerArgs,
+    /// Seconds to wait for the server before failing
+    #[arg(long, value_name = "SECONDS", default_value_t = 60)]
+    timeout: u64,
+    /// Seconds between attempts to reach the server
+    #[arg(long, value_name = "SECONDS", default_value_t = 2)]
+    interval: u64,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct RetryArgs {
+    /// Report written with `--report-csv` by an earlier run. Documents whose
+    /// latest row failed are processed, along with any `--path` given
+    report: PathBuf,
+    #[command(flatten)]
+    args: Args,
+}
+
+impl RetryArgs {
+    /// Runs the failed documents of the report again, reporting their
+    /// outcomes to the same report unless `--report-csv` names another.
+    pub async fn execute(&self, progress: Progress, cancel: CancellationToken) -> Result<()> {
+        let failed = report::failed_inputs(&self.report)?;
+        if failed.is_empty() && self.args.inputs.path.is_empty() {
+            println!("No failed documents in report {}.", self.report.display());
+            return Ok(());
+        }
+        println!(
+            "Retrying {} failed documents from report {}...",
+            failed.len(),
+            self.report.display()
+        );
+        let mut args = self.args.clone();
+        args.inputs
+            .path
+            .extend(failed.iter().map(|input| input.display().to_string()));
+        args.report_csv.get_or_insert_with(|| self.report.clone());
+        execute(&args, progress, cancel).await
+    }
+}
+
+#[derive(clap::Args, Debug)]
+pub struct CommandsArgs {
+    #[command(flatten)]
+    server: ServerArgs,
+    /// Print the commands as JSON
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct WatchArgs {
+    /// Output document to wait for; a valid document already at this path
+    /// is accepted as is
+    expected_path: PathBuf,
+    #[command(flatten)]
+    file: ConfigFileArgs,
+    #[command(flatten)]
+    limits: WatchConfigArgs,
+}
+
+async fn validate(args: &InputArgs) -> Result<()> {
+    let inputs = inputs::resolve(args).await?;
+    let mut failed = 0;
+    for input in &inputs {
+        let problems = inputs::check(input).await;
+        if problems.is_empty() {
+            println!("OK      {}", input.display());
+            continue;
+        }
+        failed += 1;
+        for problem in problems {
+            println!("FAILED  {} {}", input.display(), problem);
+        }
+    }
+    println!(
+        "{} of {} inputs passed validation.",
+        inputs.len() - failed,
+        inputs.len()
+    );
+    if failed > 0 {
+        return Err(anyhow!("{} inputs failed validation", failed));
+    }
+    Ok(())
+}
+
+async fn watch(args: &WatchArgs) -> Result<()> {
+    let config = RunConfig::from_watch_args(&args.file, &args.limits).await?;
+    let path = &args.expected_path;
+    let baseline = modified(path).await;
+    if baseline.is_some() && validate_output(path).await.is_ok() {
+        println!("Document already present at path: {}", path.display());
+        println!("{}", path.display());
+        return Ok(());
+    }
+    let phase = format!("Waiting for document at path {}", path.display());
+    with_limits(&phase, config.watch, &config, || {
+        watch_output(
+            path.clone(),
+            baseline,
+            config.watch_grace,
+            config.poll_interval,
+        )
+    })
+    .await?;
+    validate_settled(
+        path,
+        config.watch_grace,
+        config.poll_interval,
+        config.validate_retries,
+    )
+    .await?;
+    println!("{}", path.display());
+    Ok(())
+}
+
+async fn commands(args: &CommandsArgs) -> Result<()> {
+    let server = args.server.server()?;
+    let help = server.send_request(ArgvCommand::help()).await?;
+    let help = help.trim();
+    let mut probes = HashMap::new();
+    for (name, command) in [
+        ("--all_pages", ArgvCommand::all_pages()),
+        ("--all_modules", ArgvCommand::all_modules()),
+    ] {
+        let available = server
+            .send_request(command)
+            .await
+            .is_ok_and(|response| !response.trim().is_empty());
+        probes.insert(name, available);
+    }
+    let status = |name: &str| match probes.get(name) {
+        Some(true) => "available",
+        Some(false) => "no response",
+        None => "not probed",
+    };
+    if args.json {
+        let commands: Vec<_> = KNOWN_COMMANDS
+            .iter()
+            .map(|(name, description)| {
+                json!({"command": name, "description": description, "status": status(name)})
+            })
+            .collect();
+        let help = (!help.is_empty()).then_some(help);
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&json!({"help": help, "commands": commands}))?
+        );
+        return Ok(());
+    }
+    if !help.is_empty() {
+        println!("{}", help);
+        println!();
+    }
+    for (name, description) in KNOWN_COMMANDS {
+        println!("{:<14} {:<40} {}", name, description, status(name));
+    }
+    Ok(())
+}
+
+async fn clean(args: &CleanArgs) -> Result<()> {
+    let server = args.server.server()?;
+    if let Some(stale_after) = args.stale_after.map(Duration::from_secs) {
+        if let Some(age) = lease::held_elsewhere(server.address(), stale_after).await {
+            println!(
+                "{} tabs at {} are in use by a run active {}s ago. Leaving them open.",
+                TAB_NAME,
+                server.address(),
+                age.as_secs()
+            );
+            return Ok(());
+        }
+        lease::remove(server.address()).await;
+    }
+    let closed = close_batch_tabs(&server, &RunConfig::default()).await?;
+    println!("Closed {} {} tabs.", closed, TAB_NAME);
+    Ok(())
+}
+
+async fn wait_ready(args: &WaitReadyArgs) -> Result<()> {
+    let server = args.server.server()?;
+    let interval = Duration::from_secs(args.interval.max(1));
+    let started = Instant::now();
+    println!("Waiting for Umi-OCR at {}...", server.address());
+    loop {
+        let attempt = Instant::now();
+        let error = match with_timeout("Request", Some(interval), tabs(&server)).await {
+            Ok(_) => {
+                println!(
+                    "Umi-OCR at {} is ready after {}s.",
+                    server.address(),
+                    started.elapsed().as_secs()
+                );
+                return Ok(());
+            }
+            Err(error) => error,
+        };
+        let elapsed = started.elapsed();
+        if elapsed >= Duration::from_secs(args.timeout) {
+            return Err(anyhow!(
+                "Umi-OCR at {} did not respond within {}s: {}",
+                server.address(),
+                args.timeout,
+                error
+            ));
+        }
+        println!(
+            "Still waiting for Umi-OCR at {} ({}s so far): {}",
+            server.address(),
+            elapsed.as_secs(),
+            error
+        );
+        sleep(interval.saturating_sub(attempt.elapsed())).await;
+    }
+}
+
+impl Command {
+    /// Runs the subcommand until it finishes or `cancel` is triggered.
+    pub async fn run(&self, progress: Progress, cancel: CancellationToken) -> Result<()> {
+        match self {
+            Command::Validate(inputs) => cancellable(&cancel, validate(inputs)).await,
+            Command::Watch(args) => cancellable(&cancel, watch(args)).await,
+            Command::Commands(args) => cancellable(&cancel, commands(args)).await,
+            Command::Clean(args) => cancellable(&cancel, clean(args)).await,
+            Command::WaitReady(args) => cancellable(&cancel, wait_ready(args)).await,
+            Command::Retry(args) => args.execute(progress, cancel).await,
+        }
+    }
+}
@@ -0,0 +1,246 @@
+use super::checks::{check_success, tag_output};
+use super::document::{process_document, run_batch_all, Recorder};
+use super::files::{check_existing, dispose_source, flatten_output};
+use super::limits::Cancelled;
+use super::tab::close_batch_tabs;
+use super::{Args, DELAY};
+use crate::config::RunConfig;
+use crate::inputs::InputStream;
+use crate::pdf;
+use crate::server::Server;
+use anyhow::{anyhow, Result};
+use futures::future::join_all;
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::Mutex as AsyncMutex;
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, Instant};
+use tokio_util::sync::CancellationToken;
+
+/// Flag set once the `--cancel-file` appears, polled until it is dropped.
+pub(super) struct CancelFile {
+    seen: Arc<AtomicBool>,
+    task: JoinHandle<()>,
+}
+
+impl CancelFile {
+    pub(super) fn watch(path: PathBuf) -> Self {
+        let seen = Arc::new(AtomicBool::new(false));
+        let flag = seen.clone();
+        let task = tokio::spawn(async move {
+            while !path.exists() {
+                sleep(DELAY).await;
+            }
+            println!(
+                "Cancel file detected at path: {}. Finishing the current document...",
+                path.display()
+            );
+            flag.store(true, Ordering::Relaxed);
+        });
+        Self { seen, task }
+    }
+
+    pub(super) fn is_seen(&self) -> bool {
+        self.seen.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for CancelFile {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+#[derive(Default)]
+pub(super) struct WorkerReport {
+    pub(super) processed: usize,
+    pub(super) failed: usize,
+    pub(super) disposed: Vec<(PathBuf, String)>,
+    pub(super) error: Option<anyhow::Error>,
+}
+
+pub(super) struct Dispatch {
+    pub(super) requeued: Mutex<VecDeque<PathBuf>>,
+    pub(super) inputs: AsyncMutex<InputStream>,
+    pub(super) exhausted: AtomicBool,
+    pub(super) cancelled: Option<CancelFile>,
+    pub(super) interrupted: CancellationToken,
+    pub(super) stopped: AtomicBool,
+}
+
+impl Dispatch {
+    pub(super) fn is_cancelled(&self) -> bool {
+        self.cancelled.as_ref().is_some_and(CancelFile::is_seen)
+    }
+
+    pub(super) fn is_halted(&self) -> bool {
+        self.stopped.load(Ordering::Relaxed)
+            || self.is_cancelled()
+            || self.interrupted.is_cancelled()
+    }
+
+    pub(super) fn has_remaining(&self) -> bool {
+        !self.exhausted.load(Ordering::Relaxed) || !self.requeued.lock().unwrap().is_empty()
+    }
+
+    pub(super) async fn next(&self) -> Option<PathBuf> {
+        if self.is_halted() {
+            return None;
+        }
+        let requeued = self.requeued.lock().unwrap().pop_front();
+        if requeued.is_some() {
+            return requeued;
+        }
+        let source = self.inputs.lock().await.next().await;
+        if source.is_none() {
+            self.exhausted.store(true, Ordering::Relaxed);
+        }
+        source
+    }
+
+    pub(super) fn requeue(&self, source: PathBuf) {
+        self.requeued.lock().unwrap().push_front(source);
+    }
+}
+
+pub(super) async fn worker(
+    args: &Args,
+    config: &RunConfig,
+    servers: &[Server],
+    server: &Server,
+    dispatch: &Dispatch,
+    recorder: &Recorder<'_>,
+) -> WorkerReport {
+    let mut report = WorkerReport::default();
+    while let Some(source) = dispatch.next().await {
+        let started = Instant::now();
+        match check_existing(args, recorder.hashes, &source).await {
+            Ok(true) => {}
+            Ok(false) => {
+                recorder.skipped(&source, started.elapsed());
+                continue;
+            }
+            Err(error) => {
+                println!("{}", error);
+                recorder.failed(&source, started.elapsed(), &error);
+                report.failed += 1;
+                continue;
+            }
+        }
+        let output = match process_document(args, config, server, &source, recorder).await {
+            Ok(output) => match check_success(args, &output).await {
+                Ok(()) => flatten_output(args, &output).await,
+                Err(error) => Err(error),
+            },
+            Err(error) => Err(error),
+        };
+        let output = match output {
+            Ok(output) => output,
+            Err(error) if error.is::<Cancelled>() => {
+                dispatch.requeue(source);
+                return report;
+            }
+            Err(_) if !server.is_healthy() && servers.iter().any(Server::is_healthy) => {
+                println!(
+                    "Umi-OCR at {} is unreachable. Requeueing {}...",
+                    server.address(),
+                    source.display()
+                );
+                dispatch.requeue(source);
+                return report;
+            }
+            Err(error) => {
+                println!("{}", error);
+                recorder.failed(&source, started.elapsed(), &error);
+                report.failed += 1;
+                continue;
+            }
+        };
+        if args.flatten_output.is_some() {
+            report
+                .disposed
+                .push((source.clone(), format!("output at {}", output.display())));
+        }
+        tag_output(args, &output).await;
+        let pages = pdf::page_count(&output).await.ok();
+        recorder
+            .succeeded(&source, &output, pages, started.elapsed())
+            .await;
+        report.processed += 1;
+        match dispose_source(args, &source, &output).await {
+            Ok(Some(action)) => report.disposed.push((source, action)),
+            Ok(None) => {}
+            Err(error) => {
+                dispatch.stopped.store(true, Ordering::Relaxed);
+                report.error = Some(error);
+                return report;
+            }
+        }
+    }
+    report
+}
+
+pub(super) fn addresses(args: &Args) -> Vec<String> {
+    if args.servers.is_empty() {
+        return vec![format!("{}:{}", args.host, args.port)];
+    }
+    args.servers.clone()
+}
+
+pub(super) fn servers(args: &Args) -> Result<Vec<Server>> {
+    addresses(args)
+        .iter()
+        .map(|address| Server::new(address, &args.client))
+        .collect()
+}
+
+pub(super) async fn run_batch_all_servers(
+    args: &Args,
+    config: &RunConfig,
+    servers: &[Server],
+    inputs: &[PathBuf],
+    recorder: &Recorder<'_>,
+) -> Result<()> {
+    let mut groups = vec![Vec::new(); servers.len()];
+    for (index, input) in inputs.iter().enumerate() {
+        groups[index % servers.len()].push(input.clone());
+    }
+    let errors: Vec<anyhow::Error> = join_all(
+        servers
+            .iter()
+            .zip(&groups)
+            .filter(|(_, group)| !group.is_empty())
+            .map(|(server, group)| run_batch_all(args, config, server, group, recorder)),
+    )
+    .await
+    .into_iter()
+    .filter_map(Result::err)
+    .collect();
+    match errors.len() {
+        0 => Ok(()),
+        1 => Err(errors.into_iter().next().unwrap()),
+        _ => Err(anyhow!(
+            "{}",
+            errors
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join("; ")
+        )),
+    }
+}
+
+pub(super) async fn close_healthy_tabs(
+    args: &Args,
+    config: &RunConfig,
+    servers: &[Server],
+) -> Result<()> {
+    if args.reuse_page.is_none() {
+        for server in servers.iter().filter(|server| server.is_healthy()) {
+            close_batch_tabs(server, config).await?;
+        }
+    }
+    Ok(())
+}
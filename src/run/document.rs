@@ -0,0 +1,575 @@
+use super::checks::{check_success, tag_output};
+use super::files::{
+    check_existing, dispose_source, file_name, flatten_output, move_file, put_back, reserve_name,
+    restore_stash, stash_existing,
+};
+use super::limits::{cancellable, pause, with_limits};
+use super::output::{
+    check_writable, computed_output, discover_output, modified, output_dir, output_format,
+    reported_output, snapshot_dir, tab_format, validate_settled, watch_limits, watch_output,
+    watch_outputs,
+};
+use super::tab::{add_docs, doc_start, heartbeat, prepare_tab, report_queue, set_formats};
+use super::{Args, ExistingOutput};
+use crate::config::RunConfig;
+use crate::metrics::Metrics;
+use crate::paths::OutputFormat;
+use crate::progress::{Eta, Phase, Progress, ProgressEvent};
+use crate::report::{CsvReport, HashLog, Row};
+use crate::server::Server;
+use crate::{paths, pdf};
+use anyhow::{anyhow, Result};
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use std::{process, slice};
+use tokio::fs;
+use tokio::time::Instant;
+use tracing::{info_span, instrument, Instrument};
+
+pub(super) struct Recorder<'a> {
+    pub(super) metrics: &'a Arc<Metrics>,
+    pub(super) csv: Option<&'a CsvReport>,
+    pub(super) hashes: Option<&'a HashLog>,
+    pub(super) progress: &'a Progress,
+    pub(super) eta: &'a Eta,
+}
+
+impl Recorder<'_> {
+    pub(super) fn phase(&self, inputs: &[impl AsRef<Path>], server: &Server, phase: Phase) {
+        for input in inputs {
+            self.progress.emit(ProgressEvent::Phase {
+                input: input.as_ref().to_path_buf(),
+                server: server.address().to_string(),
+                phase,
+            });
+        }
+    }
+
+    pub(super) async fn succeeded(
+        &self,
+        input: &Path,
+        output: &Path,
+        pages: Option<usize>,
+        elapsed: Duration,
+    ) {
+        if let Some(hashes) = self.hashes {
+            hashes.record(input, output).await;
+        }
+        self.metrics.record(true, pages);
+        if let Some(csv) = self.csv {
+            csv.record(Row {
+                input,
+                output: Some(output),
+                pages,
+                elapsed,
+                error: None,
+                skipped: false,
+            });
+        }
+        self.progress.emit(ProgressEvent::Succeeded {
+            input: input.to_path_buf(),
+            output: output.to_path_buf(),
+            pages,
+        });
+        let documents = self.metrics.queue_depth.load(Ordering::Relaxed) as usize;
+        let Some(pages) = pages else { return };
+        let Some(remaining) = self.eta.finished(pages, documents) else {
+            return;
+        };
+        if documents > 0 {
+            println!(
+                "About {} left for the {} documents queued so far.",
+                humantime::format_duration(Duration::from_secs(remaining.as_secs())),
+                documents
+            );
+        }
+        self.progress.emit(ProgressEvent::Estimate {
+            remaining,
+            documents,
+        });
+    }
+
+    pub(super) fn skipped(&self, input: &Path, elapsed: Duration) {
+        self.metrics.skip();
+        if let Some(csv) = self.csv {
+            csv.record(Row {
+                input,
+                output: None,
+                pages: None,
+                elapsed,
+                error: None,
+                skipped: true,
+            });
+        }
+        self.progress.emit(ProgressEvent::Skipped {
+            input: input.to_path_buf(),
+        });
+    }
+
+    pub(super) fn failed(&self, input: &Path, elapsed: Duration, error: &anyhow::Error) {
+        self.metrics.record(false, None);
+        if let Some(csv) = self.csv {
+            csv.record(Row {
+                input,
+                output: None,
+                pages: None,
+                elapsed,
+                error: Some(error),
+                skipped: false,
+            });
+        }
+        self.progress.emit(ProgressEvent::Failed {
+            input: input.to_path_buf(),
+            error: error.to_string(),
+        });
+    }
+}
+
+#[instrument(
+    name = "document",
+    skip_all,
+    fields(server = server.address(), path = %source.display()),
+    err(Display)
+)]
+async fn process(
+    args: &Args,
+    config: &RunConfig,
+    server: &Server,
+    source: &Path,
+    format: Option<OutputFormat>,
+    recorder: &Recorder<'_>,
+) -> Result<PathBuf> {
+    let inputs = [source];
+    recorder.phase(&inputs, server, Phase::Tab);
+    prepare_tab(args, config, server).await?;
+
+    let path = paths::umi_path(source);
+
+    recorder.phase(&inputs, server, Phase::Add);
+    add_docs(server, slice::from_ref(&path), config).await?;
+    pause(config, config.add_delay).await?;
+
+    let grace = config.watch_grace;
+    let watch = watch_limits(config, &inputs).await;
+    let dir = output_dir(source);
+    set_formats(server, config, &format.into_iter().collect()).await?;
+    if args.auto_output {
+        let before = snapshot_dir(dir).await?;
+        recorder.phase(&inputs, server, Phase::Start);
+        doc_start(server, config).await?;
+        let phase = format!("Waiting for a new document in directory {}", dir.display());
+        recorder.phase(&inputs, server, Phase::Watch);
+        let output = cancellable(
+            &config.cancel,
+            heartbeat(
+                args,
+                server,
+                with_limits(&phase, watch, config, || {
+                    discover_output(source, &before, grace, config.poll_interval)
+                }),
+            ),
+        )
+        .instrument(info_span!("watch", dir = %dir.display()))
+        .await?;
+        recorder.phase(&inputs, server, Phase::Validate);
+        cancellable(
+            &config.cancel,
+            validate_settled(
+                &output,
+                grace,
+                config.poll_interval,
+                config.validate_retries,
+            ),
+        )
+        .await?;
+        return Ok(output);
+    }
+
+    let computed = paths::output_path_as(source, format.unwrap_or(OutputFormat::LayeredPdf));
+    let stash = stash_existing(args, &computed).await?;
+    let baseline = modified(&computed).await;
+
+    let result = async {
+        recorder.phase(&inputs, server, Phase::Start);
+        let start_time = SystemTime::now();
+        let mut reported = doc_start(server, config).await?;
+        let (output, baseline) = reported_output(
+            &mut reported,
+            source,
+            (computed.clone(), baseline),
+            start_time,
+        )
+        .await;
+
+        let phase = format!("Waiting for document at path {}", output.display());
+        recorder.phase(&inputs, server, Phase::Watch);
+        cancellable(
+            &config.cancel,
+            heartbeat(
+                args,
+                server,
+                with_limits(&phase, watch, config, || {
+                    watch_output(output.clone(), baseline, grace, config.poll_interval)
+                }),
+            ),
+        )
+        .instrument(info_span!("watch", output = %output.display()))
+        .await?;
+        recorder.phase(&inputs, server, Phase::Validate);
+        cancellable(
+            &config.cancel,
+            validate_settled(
+                &output,
+                grace,
+                config.poll_interval,
+                config.validate_retries,
+            ),
+        )
+        .await?;
+        Ok(output)
+    }
+    .await;
+    restore_stash(&computed, stash, result).await
+}
+
+async fn merge_outputs(parts: &[PathBuf], output: &Path) -> Result<()> {
+    let extension = |path: &Path| {
+        path.extension()
+            .map(|extension| extension.to_ascii_lowercase())
+    };
+    if let Some(part) = parts
+        .iter()
+        .find(|part| extension(part) != extension(output))
+    {
+        return Err(anyhow!(
+            "Error merging parts: {} is not the same format as output {}",
+            part.display(),
+            output.display()
+        ));
+    }
+    if output
+        .extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("pdf"))
+    {
+        return pdf::merge(parts, output).await;
+    }
+    let mut merged = Vec::new();
+    for part in parts {
+        merged.extend(fs::read(part).await?);
+    }
+    fs::write(output, merged)
+        .await
+        .map_err(|error| anyhow!("Error writing output {}: {}", output.display(), error))
+}
+
+static WORK_DIRS: AtomicUsize = AtomicUsize::new(0);
+
+async fn work_dir() -> Result<PathBuf> {
+    let dir = std::env::temp_dir().join(format!(
+        "umi-http-{}-{}",
+        process::id(),
+        WORK_DIRS.fetch_add(1, Ordering::Relaxed)
+    ));
+    fs::create_dir_all(&dir)
+        .await
+        .map_err(|error| anyhow!("Error creating directory {}: {}", dir.display(), error))?;
+    Ok(dir)
+}
+
+/// Where an output produced in a work directory ends up, honouring
+/// `--existing-output rename`.
+async fn final_output(args: &Args, source: &Path, produced: &Path) -> Result<PathBuf> {
+    let output = match args.auto_output {
+        true => output_dir(source).join(file_name(produced)?),
+        false => computed_output(args, source),
+    };
+    if args.existing_output == ExistingOutput::Rename && fs::metadata(&output).await.is_ok() {
+        return Ok(reserve_name(output_dir(&output), &file_name(&output)?).await?);
+    }
+    Ok(output)
+}
+
+async fn process_parts(
+    args: &Args,
+    config: &RunConfig,
+    server: &Server,
+    source: &Path,
+    recorder: &Recorder<'_>,
+    dir: &Path,
+    max_pages: usize,
+) -> Result<PathBuf> {
+    let format = tab_format(args, source);
+    let parts = pdf::split(source, max_pages, dir).await?;
+    println!(
+        "Split {} into {} parts of up to {} pages.",
+        source.display(),
+        parts.len(),
+        max_pages
+    );
+    let mut outputs = Vec::new();
+    for (index, part) in parts.iter().enumerate() {
+        println!("Processing part {} of {}...", index + 1, parts.len());
+        outputs.push(process(args, config, server, part, format, recorder).await?);
+    }
+    let output = final_output(args, source, &outputs[0]).await?;
+    merge_outputs(&outputs, &output).await?;
+    println!(
+        "Merged {} parts into output at path: {}",
+        outputs.len(),
+        output.display()
+    );
+    Ok(output)
+}
+
+async fn process_alias(
+    args: &Args,
+    config: &RunConfig,
+    server: &Server,
+    source: &Path,
+    recorder: &Recorder<'_>,
+    dir: &Path,
+    input_type: &str,
+) -> Result<PathBuf> {
+    let stem = source
+        .file_stem()
+        .ok_or_else(|| anyhow!("Source path {} has no file name", source.display()))?;
+    let format = tab_format(args, source);
+    let alias = dir.join(stem).with_extension(input_type);
+    if fs::hard_link(source, &alias).await.is_err() {
+        fs::copy(source, &alias).await.map_err(|error| {
+            anyhow!(
+                "Error copying {} to {}: {}",
+                source.display(),
+                alias.display(),
+                error
+            )
+        })?;
+    }
+    println!(
+        "Passing {} to Umi-OCR as {}.",
+        source.display(),
+        alias.display()
+    );
+    let produced = process(args, config, server, &alias, format, recorder).await?;
+    let output = final_output(args, source, &produced).await?;
+    move_file(&produced, &output)
+        .await
+        .map_err(|error| anyhow!("Error moving output to {}: {}", output.display(), error))?;
+    println!("Output moved to path: {}", output.display());
+    Ok(output)
+}
+
+/// Processes PDFs longer than `--max-pages` as separate parts, and inputs
+/// whose extension does not match `--input-type` under a name that does, in
+/// a temporary directory. Other inputs are processed in place.
+pub(super) async fn process_document(
+    args: &Args,
+    config: &RunConfig,
+    server: &Server,
+    source: &Path,
+    recorder: &Recorder<'_>,
+) -> Result<PathBuf> {
+    let input_type = args.input_type.as_deref().filter(|input_type| {
+        !source
+            .extension()
+            .is_some_and(|extension| extension.eq_ignore_ascii_case(input_type))
+    });
+    let max_pages = match (args.max_pages, input_type) {
+        (Some(max_pages), None) => pdf::page_count(source)
+            .await
+            .is_ok_and(|pages| pages > max_pages)
+            .then_some(max_pages),
+        _ => None,
+    };
+    check_writable(output_dir(source)).await?;
+    if input_type.is_none() && max_pages.is_none() {
+        let format = tab_format(args, source);
+        return process(args, config, server, source, format, recorder).await;
+    }
+    let dir = work_dir().await?;
+    let result = match (max_pages, input_type) {
+        (Some(max_pages), _) => {
+            process_parts(args, config, server, source, recorder, &dir, max_pages).await
+        }
+        (None, Some(input_type)) => {
+            process_alias(args, config, server, source, recorder, &dir, input_type).await
+        }
+        (None, None) => unreachable!(),
+    };
+    if let Err(error) = fs::remove_dir_all(&dir).await {
+        println!("Could not remove directory {}: {}", dir.display(), error);
+    }
+    result
+}
+
+#[instrument(
+    name = "batch",
+    skip_all,
+    fields(server = server.address(), count = inputs.len()),
+    err(Display)
+)]
+pub(super) async fn run_batch_all(
+    args: &Args,
+    config: &RunConfig,
+    server: &Server,
+    inputs: &[PathBuf],
+    recorder: &Recorder<'_>,
+) -> Result<()> {
+    let started = Instant::now();
+    let mut queued = Vec::new();
+    for input in inputs {
+        if check_existing(args, recorder.hashes, input).await? {
+            queued.push(input.clone());
+        } else {
+            recorder.skipped(input, started.elapsed());
+        }
+    }
+    if queued.is_empty() {
+        return Ok(());
+    }
+    let inputs = queued.as_slice();
+    recorder.phase(inputs, server, Phase::Tab);
+    prepare_tab(args, config, server).await?;
+
+    let paths: Vec<String> = inputs.iter().map(|input| paths::umi_path(input)).collect();
+
+    recorder.phase(inputs, server, Phase::Add);
+    add_docs(server, &paths, config).await?;
+    pause(config, config.add_delay).await?;
+
+    for dir in inputs
+        .iter()
+        .map(|input| output_dir(input))
+        .collect::<BTreeSet<_>>()
+    {
+        check_writable(dir).await?;
+    }
+
+    let formats: Vec<OutputFormat> = inputs
+        .iter()
+        .map(|input| output_format(args, input).unwrap_or(OutputFormat::LayeredPdf))
+        .collect();
+    let selected: BTreeSet<OutputFormat> = inputs
+        .iter()
+        .filter_map(|input| output_format(args, input))
+        .collect();
+    if !selected.is_empty() {
+        set_formats(server, config, &formats.iter().copied().collect()).await?;
+    }
+    let mut stashes = Vec::new();
+    let result = async {
+        let mut computed = Vec::new();
+        for (input, format) in inputs.iter().zip(&formats) {
+            let output = paths::output_path_as(input, *format);
+            stashes.push((output.clone(), stash_existing(args, &output).await?));
+            let baseline = modified(&output).await;
+            computed.push((output, baseline));
+        }
+
+        let watch = watch_limits(config, inputs).await;
+
+        recorder.phase(inputs, server, Phase::Start);
+        let start_time = SystemTime::now();
+        let mut reported = doc_start(server, config).await?;
+        let mut watched = Vec::new();
+        for (input, computed) in inputs.iter().zip(computed) {
+            watched.push(reported_output(&mut reported, input, computed, start_time).await);
+        }
+        let outputs: Vec<PathBuf> = watched.iter().map(|(output, _)| output.clone()).collect();
+
+        recorder.phase(inputs, server, Phase::Watch);
+        let watching = async {
+            Ok(watch_outputs(
+                &watched,
+                config.watch_grace,
+                config.poll_interval,
+                config.validate_retries,
+                watch.timeout,
+            )
+            .await)
+        };
+        let results = cancellable(
+            &config.cancel,
+            heartbeat(args, server, report_queue(args, server, watching)),
+        )
+        .instrument(info_span!("watch", count = watched.len()))
+        .await?;
+        Ok((outputs, results))
+    }
+    .await;
+    let (outputs, results) = match result {
+        Ok(watched) => watched,
+        Err(error) => {
+            for (computed, stash) in stashes {
+                if let Some(stash) = stash {
+                    if let Err(error) = put_back(&computed, &stash).await {
+                        println!("{}", error);
+                    }
+                }
+            }
+            return Err(error);
+        }
+    };
+
+    let mut disposed = Vec::new();
+    let mut failed = 0;
+    let settled = inputs.iter().zip(outputs).zip(results);
+    for (((source, output), result), (computed, stash)) in settled.zip(stashes) {
+        let output = restore_stash(&computed, stash, result.map(|()| output)).await;
+        let output = match output {
+            Ok(output) => check_success(args, &output)
+                .await
+                .inspect_err(|error| println!("{}", error))
+                .map(|()| output),
+            Err(error) => Err(error),
+        };
+        let output = match output {
+            Ok(output) => flatten_output(args, &output)
+                .await
+                .inspect_err(|error| println!("{}", error)),
+            Err(error) => Err(error),
+        };
+        let output = match output {
+            Ok(output) => output,
+            Err(error) => {
+                recorder.failed(source, started.elapsed(), &error);
+                failed += 1;
+                continue;
+            }
+        };
+        if args.flatten_output.is_some() {
+            disposed.push((source, format!("output at {}", output.display())));
+        }
+        let output = &output;
+        tag_output(args, output).await;
+        let pages = pdf::page_count(output).await.ok();
+        recorder
+            .succeeded(source, output, pages, started.elapsed())
+            .await;
+        if let Some(action) = dispose_source(args, source, output).await? {
+            disposed.push((source, action));
+        }
+    }
+    print_disposed(&disposed);
+    if failed > 0 {
+        return Err(anyhow!(
+            "{} of {} documents did not produce a valid output",
+            failed,
+            inputs.len()
+        ));
+    }
+    Ok(())
+}
+
+pub(super) fn print_disposed(disposed: &[(impl AsRef<Path>, String)]) {
+    if disposed.is_empty() {
+        return;
+    }
+    println!("Sources handled:");
+    for (source, action) in disposed {
+        println!("  {} {}", source.as_ref().display(), action);
+    }
+}
@@ -0,0 +1,225 @@
+use super::output::{computed_output, output_dir, same_file};
+use super::{Args, ExistingOutput};
+use crate::paths::OutputFormat;
+use crate::report::HashLog;
+use anyhow::{anyhow, Result};
+use std::io;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+
+/// Creates an empty file in `dir` named `name`, or `name` numbered from 2
+/// onwards if that is taken, and returns its path.
+pub(super) async fn reserve_name(dir: &Path, name: &str) -> io::Result<PathBuf> {
+    let suffixed = OutputFormat::ALL.iter().find_map(|format| {
+        name.strip_suffix(format.suffix())
+            .map(|stem| (stem, format.suffix()))
+    });
+    let (stem, suffix) = match suffixed {
+        Some(suffixed) => suffixed,
+        None => match name.rfind('.') {
+            Some(index) if index > 0 => name.split_at(index),
+            _ => (name, ""),
+        },
+    };
+    let mut index = 1;
+    loop {
+        let target = match index {
+            1 => dir.join(name),
+            _ => dir.join(format!("{}-{}{}", stem, index, suffix)),
+        };
+        match fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&target)
+            .await
+        {
+            Ok(_) => return Ok(target),
+            Err(error) if error.kind() == io::ErrorKind::AlreadyExists => index += 1,
+            Err(error) => return Err(error),
+        }
+    }
+}
+
+pub(super) fn file_name(path: &Path) -> Result<String> {
+    Ok(path
+        .file_name()
+        .ok_or_else(|| anyhow!("Output path {} has no file name", path.display()))?
+        .to_string_lossy()
+        .into_owned())
+}
+
+pub(super) async fn move_file(from: &Path, to: &Path) -> io::Result<()> {
+    if fs::rename(from, to).await.is_err() {
+        fs::copy(from, to).await?;
+        fs::remove_file(from).await?;
+    }
+    Ok(())
+}
+
+pub(super) async fn flatten_output(args: &Args, output: &Path) -> Result<PathBuf> {
+    let Some(dir) = &args.flatten_output else {
+        return Ok(output.to_path_buf());
+    };
+    if output.parent().is_some_and(|parent| same_file(parent, dir)) {
+        return Ok(output.to_path_buf());
+    }
+    let name = file_name(output)?;
+    let error = |error| anyhow!("Error flattening output {}: {}", output.display(), error);
+    fs::create_dir_all(dir).await.map_err(error)?;
+    let target = reserve_name(dir, &name).await.map_err(error)?;
+    move_file(output, &target).await.map_err(error)?;
+    println!("Output {} moved to {}.", output.display(), target.display());
+    Ok(target)
+}
+
+/// Applies `--existing-output skip` and `fail` before an input is queued.
+/// Returns false if the input should be skipped.
+pub(super) async fn check_existing(
+    args: &Args,
+    hashes: Option<&HashLog>,
+    source: &Path,
+) -> Result<bool> {
+    if args.auto_output
+        || !matches!(
+            args.existing_output,
+            ExistingOutput::Skip | ExistingOutput::Fail
+        )
+    {
+        return Ok(true);
+    }
+    let output = computed_output(args, source);
+    if fs::metadata(&output).await.is_err() {
+        return Ok(true);
+    }
+    if args.existing_output == ExistingOutput::Fail {
+        return Err(anyhow!(
+            "Output already exists at path: {}",
+            output.display()
+        ));
+    }
+    if let Some(false) = match hashes {
+        Some(hashes) => hashes.matches(source, &output).await,
+        None => None,
+    } {
+        println!(
+            "Output at path {} does not match the hash recorded for it. Processing {} again.",
+            output.display(),
+            source.display()
+        );
+        return Ok(true);
+    }
+    println!(
+        "Output already exists at path {}. Skipping {}.",
+        output.display(),
+        source.display()
+    );
+    Ok(false)
+}
+
+/// Under `--existing-output rename`, moves an existing output out of the way
+/// so Umi-OCR writes a fresh one, and returns where it was moved.
+pub(super) async fn stash_existing(args: &Args, output: &Path) -> Result<Option<PathBuf>> {
+    if args.existing_output != ExistingOutput::Rename || fs::metadata(output).await.is_err() {
+        return Ok(None);
+    }
+    let error = |error| {
+        anyhow!(
+            "Error moving existing output {} aside: {}",
+            output.display(),
+            error
+        )
+    };
+    let stash = reserve_name(output_dir(output), &file_name(output)?)
+        .await
+        .map_err(error)?;
+    move_file(output, &stash).await.map_err(error)?;
+    println!(
+        "Existing output moved to {} while the new one is produced.",
+        stash.display()
+    );
+    Ok(Some(stash))
+}
+
+/// Puts a stashed output back at `computed` and moves the new output, if it
+/// was written there, to the stash's numbered name.
+pub(super) async fn restore_stash(
+    computed: &Path,
+    stash: Option<PathBuf>,
+    result: Result<PathBuf>,
+) -> Result<PathBuf> {
+    let Some(stash) = stash else {
+        return result;
+    };
+    let error = |error| {
+        anyhow!(
+            "Error restoring existing output {}: {}",
+            computed.display(),
+            error
+        )
+    };
+    match result {
+        Ok(output) if output == computed => {
+            let mut swap = stash.clone().into_os_string();
+            swap.push(".swap");
+            let swap = PathBuf::from(swap);
+            move_file(&stash, &swap).await.map_err(error)?;
+            move_file(computed, &stash).await.map_err(error)?;
+            move_file(&swap, computed).await.map_err(error)?;
+            println!(
+                "Existing output kept at {}. New output moved to {}.",
+                computed.display(),
+                stash.display()
+            );
+            Ok(stash)
+        }
+        result => {
+            put_back(computed, &stash).await?;
+            result
+        }
+    }
+}
+
+/// Moves a stashed output back to `computed`, where the run found it.
+pub(super) async fn put_back(computed: &Path, stash: &Path) -> Result<()> {
+    move_file(stash, computed).await.map_err(|error| {
+        anyhow!(
+            "Error restoring existing output {}: {}",
+            computed.display(),
+            error
+        )
+    })
+}
+
+pub(super) async fn dispose_source(
+    args: &Args,
+    source: &Path,
+    output: &Path,
+) -> Result<Option<String>> {
+    if let Some(dir) = &args.move_source {
+        let name = source
+            .file_name()
+            .ok_or_else(|| anyhow!("Source path {} has no file name", source.display()))?
+            .to_string_lossy()
+            .into_owned();
+        let error = |error| anyhow!("Error moving source {}: {}", source.display(), error);
+        fs::create_dir_all(dir).await.map_err(error)?;
+        let target = reserve_name(dir, &name).await.map_err(error)?;
+        move_file(source, &target).await.map_err(error)?;
+        println!("Source {} moved to {}.", source.display(), target.display());
+        return Ok(Some(format!("moved to {}", target.display())));
+    }
+    if args.delete_source {
+        if same_file(source, output) {
+            return Err(anyhow!(
+                "Refusing to delete source {} as it is also the output",
+                source.display()
+            ));
+        }
+        fs::remove_file(source)
+            .await
+            .map_err(|error| anyhow!("Error deleting source {}: {}", source.display(), error))?;
+        println!("Source {} deleted.", source.display());
+        return Ok(Some("deleted".to_string()));
+    }
+    Ok(None)
+}
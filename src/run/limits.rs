@@ -0,0 +1,232 @@
+use crate::config::{PhaseLimits, RunConfig};
+use anyhow::{anyhow, Result};
+use std::fmt;
+use std::future::Future;
+use std::time::Duration;
+use tokio::time::{self, sleep};
+use tokio_util::sync::CancellationToken;
+
+pub(super) async fn with_timeout<T>(
+    phase: &str,
+    timeout: Option<Duration>,
+    future: impl Future<Output = Result<T>>,
+) -> Result<T> {
+    let Some(timeout) = timeout else {
+        return future.await;
+    };
+    time::timeout(timeout, future)
+        .await
+        .map_err(|_| anyhow!("{} timed out after {}s", phase, timeout.as_secs()))?
+}
+
+/// Error returned once the run's cancellation token has been triggered.
+#[derive(Debug)]
+pub struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Cancelled")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+pub(super) fn check_cancelled(config: &RunConfig) -> Result<()> {
+    if config.cancel.is_cancelled() {
+        return Err(Cancelled.into());
+    }
+    Ok(())
+}
+
+/// Runs `future` unless `cancel` is triggered first.
+pub(super) async fn cancellable<T>(
+    cancel: &CancellationToken,
+    future: impl Future<Output = Result<T>>,
+) -> Result<T> {
+    tokio::select! {
+        result = future => result,
+        _ = cancel.cancelled() => Err(Cancelled.into()),
+    }
+}
+
+pub(super) async fn pause(config: &RunConfig, duration: Duration) -> Result<()> {
+    cancellable(&config.cancel, async {
+        sleep(duration).await;
+        Ok(())
+    })
+    .await
+}
+
+pub(super) async fn with_limits<T, F, Fut>(
+    phase: &str,
+    limits: PhaseLimits,
+    config: &RunConfig,
+    mut attempt: F,
+) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let mut retry = 0;
+    loop {
+        check_cancelled(config)?;
+        match cancellable(
+            &config.cancel,
+            with_timeout(phase, limits.timeout, attempt()),
+        )
+        .await
+        {
+            Ok(value) => return Ok(value),
+            Err(error) if retry < limits.retries && !error.is::<Cancelled>() => {
+                config
+                    .retry_budget
+                    .take()
+                    .map_err(|exhausted| anyhow!("{}: {}", exhausted, error))?;
+                retry += 1;
+                tracing::warn!(%error, retry, "retrying");
+                println!("{}. Retrying ({}/{})...", error, retry, limits.retries);
+                pause(config, config.retry_delay).await?;
+            }
+            Err(error) => return Err(error),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::RetryBudget;
+    use std::cell::Cell;
+    use tokio::time::Instant;
+
+    const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+    fn config() -> RunConfig {
+        RunConfig {
+            retry_delay: RETRY_DELAY,
+            ..RunConfig::default()
+        }
+    }
+
+    fn limits(timeout: Option<u64>, retries: u32) -> PhaseLimits {
+        PhaseLimits {
+            timeout: timeout.map(Duration::from_secs),
+            retries,
+        }
+    }
+
+    /// An attempt that fails the first `failures` times it is made.
+    fn failing(failures: u32, attempts: &Cell<u32>) -> impl Future<Output = Result<u32>> + '_ {
+        let attempt = attempts.get() + 1;
+        attempts.set(attempt);
+        async move {
+            match attempt <= failures {
+                true => Err(anyhow!("attempt {} failed", attempt)),
+                false => Ok(attempt),
+            }
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn with_limits_retries_until_an_attempt_succeeds() {
+        let attempts = Cell::new(0);
+        let started = Instant::now();
+        let result = with_limits("Phase", limits(None, 2), &config(), || {
+            failing(2, &attempts)
+        })
+        .await;
+        assert_eq!(result.unwrap(), 3);
+        assert_eq!(started.elapsed(), RETRY_DELAY * 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn with_limits_returns_the_last_error_once_retries_run_out() {
+        let attempts = Cell::new(0);
+        let result = with_limits("Phase", limits(None, 1), &config(), || {
+            failing(u32::MAX, &attempts)
+        })
+        .await;
+        assert_eq!(result.unwrap_err().to_string(), "attempt 2 failed");
+        assert_eq!(attempts.get(), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn with_limits_stops_retrying_once_the_budget_is_spent() {
+        let config = RunConfig {
+            retry_budget: RetryBudget::new(Some(1)),
+            ..config()
+        };
+        let attempts = Cell::new(0);
+        let result = with_limits("Phase", limits(None, 5), &config, || {
+            failing(u32::MAX, &attempts)
+        })
+        .await;
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "Retry budget of 1 exhausted: attempt 2 failed"
+        );
+        assert_eq!(attempts.get(), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn with_limits_times_out_each_attempt() {
+        let started = Instant::now();
+        let result: Result<()> = with_limits("Phase", limits(Some(5), 0), &config(), || async {
+            sleep(Duration::from_secs(60)).await;
+            Ok(())
+        })
+        .await;
+        assert_eq!(result.unwrap_err().to_string(), "Phase timed out after 5s");
+        assert_eq!(started.elapsed(), Duration::from_secs(5));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn with_limits_retries_an_attempt_that_timed_out() {
+        let attempts = Cell::new(0);
+        let started = Instant::now();
+        let result = with_limits("Phase", limits(Some(5), 1), &config(), || {
+            let attempt = attempts.get() + 1;
+            attempts.set(attempt);
+            async move {
+                if attempt == 1 {
+                    sleep(Duration::from_secs(60)).await;
+                }
+                Ok(attempt)
+            }
+        })
+        .await;
+        assert_eq!(result.unwrap(), 2);
+        assert_eq!(started.elapsed(), Duration::from_secs(5) + RETRY_DELAY);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn with_limits_stops_a_pending_attempt_when_cancelled() {
+        let config = config();
+        let cancel = config.cancel.clone();
+        tokio::spawn(async move {
+            sleep(Duration::from_secs(2)).await;
+            cancel.cancel();
+        });
+        let started = Instant::now();
+        let result: Result<()> = with_limits("Phase", limits(None, 3), &config, || async {
+            sleep(Duration::from_secs(3600)).await;
+            Ok(())
+        })
+        .await;
+        assert!(result.unwrap_err().is::<Cancelled>());
+        assert_eq!(started.elapsed(), Duration::from_secs(2));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn with_limits_does_not_retry_once_cancelled() {
+        let config = config();
+        config.cancel.cancel();
+        let attempts = Cell::new(0);
+        let result = with_limits("Phase", limits(None, 3), &config, || {
+            failing(u32::MAX, &attempts)
+        })
+        .await;
+        assert!(result.unwrap_err().is::<Cancelled>());
+        assert_eq!(attempts.get(), 0);
+    }
+}
@@ -0,0 +1,521 @@
+use super::{Args, OUTPUT_EXTENSIONS};
+use crate::config::{PhaseLimits, RunConfig};
+use crate::paths::OutputFormat;
+use crate::{inputs, paths, pdf};
+use anyhow::{anyhow, Result};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::{Duration, SystemTime};
+use tokio::fs;
+use tokio::io::AsyncReadExt;
+use tokio::task::JoinSet;
+use tokio::time::{self, sleep, Instant};
+
+/// Format for `source`: its `path:format` override as given, otherwise
+/// `--output-format` with `--pdf-mode` applied to PDFs.
+pub(super) fn output_format(args: &Args, source: &Path) -> Option<OutputFormat> {
+    if let Some(format) = inputs::format_override(&args.inputs, source) {
+        return Some(format);
+    }
+    match (args.output_format, args.pdf_mode) {
+        (None | Some(OutputFormat::LayeredPdf | OutputFormat::TextPdf), Some(mode)) => {
+            Some(mode.format())
+        }
+        (format, _) => format,
+    }
+}
+
+/// Format to set on the tab for `source`. Once any input has a format
+/// override, the tab is reused across inputs, so the default is set explicitly.
+/// Parts and aliases are named after a temporary file, so their format is
+/// taken from the input they stand for.
+pub(super) fn tab_format(args: &Args, source: &Path) -> Option<OutputFormat> {
+    output_format(args, source)
+        .or(inputs::has_format_overrides(&args.inputs).then_some(OutputFormat::LayeredPdf))
+}
+
+/// Takes the output Umi-OCR reported for `source` out of `reported`, falling
+/// back to the computed one. A reported path named exactly like the computed
+/// output wins over one that only starts with the input's stem. A reported
+/// output written since `started` is not used as a baseline, as it is already
+/// this run's result.
+pub(super) async fn reported_output(
+    reported: &mut Vec<PathBuf>,
+    source: &Path,
+    computed: (PathBuf, Option<SystemTime>),
+    started: SystemTime,
+) -> (PathBuf, Option<SystemTime>) {
+    let Some(stem) = source.file_stem().and_then(|stem| stem.to_str()) else {
+        return computed;
+    };
+    let name = computed.0.file_name();
+    let position = reported
+        .iter()
+        .position(|path| *path == computed.0)
+        .or_else(|| reported.iter().position(|path| path.file_name() == name))
+        .or_else(|| {
+            reported
+                .iter()
+                .enumerate()
+                .filter(|(_, path)| named_after(path, stem) && !same_file(path, source))
+                .min_by_key(|(_, path)| path.as_os_str().len())
+                .map(|(position, _)| position)
+        });
+    let Some(position) = position else {
+        return computed;
+    };
+    let output = reported.remove(position);
+    if output == computed.0 {
+        return computed;
+    }
+    println!("Umi-OCR reported output at path: {}", output.display());
+    let baseline = modified(&output)
+        .await
+        .filter(|modified| *modified < started);
+    (output, baseline)
+}
+
+async fn settle(path: &Path, grace: Duration) -> Result<()> {
+    if grace.is_zero() {
+        return Ok(());
+    }
+    let mut last_len = fs::metadata(path).await?.len();
+    loop {
+        sleep(grace).await;
+        let len = fs::metadata(path).await?.len();
+        if len == last_len {
+            return Ok(());
+        }
+        println!(
+            "Document at path: {} is still being written...",
+            path.display()
+        );
+        last_len = len;
+    }
+}
+
+pub(super) async fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).await.ok()?.modified().ok()
+}
+
+pub(super) async fn watch_output(
+    path: PathBuf,
+    baseline: Option<SystemTime>,
+    grace: Duration,
+    poll: Duration,
+) -> Result<()> {
+    if let Some(last_modified) = baseline {
+        loop {
+            sleep(poll).await;
+            if modified(&path)
+                .await
+                .is_some_and(|current_modified| current_modified != last_modified)
+            {
+                println!("Document at path: {} has been overwritten", path.display());
+                break;
+            }
+        }
+    } else {
+        println!("Waiting for document to exist at path: {}", path.display());
+        while !path.exists() {
+            sleep(poll).await;
+        }
+        println!("Document detected at path: {}", path.display());
+    }
+    settle(&path, grace).await
+}
+
+pub(super) async fn snapshot_dir(dir: &Path) -> Result<HashMap<PathBuf, SystemTime>> {
+    let mut files = HashMap::new();
+    let mut entries = fs::read_dir(dir)
+        .await
+        .map_err(|error| anyhow!("Error reading directory {}: {}", dir.display(), error))?;
+    while let Some(entry) = entries.next_entry().await? {
+        let metadata = entry.metadata().await?;
+        if metadata.is_file() {
+            files.insert(entry.path(), metadata.modified()?);
+        }
+    }
+    Ok(files)
+}
+
+pub(super) fn has_output_extension(path: &Path) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| {
+            OUTPUT_EXTENSIONS
+                .iter()
+                .any(|known| extension.eq_ignore_ascii_case(known))
+        })
+}
+
+/// Whether the file name of `path` is `stem` followed by an extension.
+fn named_after(path: &Path, stem: &str) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str()?.strip_prefix(stem))
+        .is_some_and(|rest| rest.starts_with('.'))
+}
+
+fn pick_output(mut candidates: Vec<(PathBuf, SystemTime)>, stem: &str) -> Option<PathBuf> {
+    candidates.sort_by_key(|(_, modified)| Reverse(*modified));
+    let position = candidates
+        .iter()
+        .position(|(path, _)| named_after(path, stem))
+        .or_else(|| {
+            candidates
+                .iter()
+                .position(|(path, _)| has_output_extension(path))
+        })
+        .or((!candidates.is_empty()).then_some(0))?;
+    Some(candidates.swap_remove(position).0)
+}
+
+pub(super) async fn discover_output(
+    source: &Path,
+    before: &HashMap<PathBuf, SystemTime>,
+    grace: Duration,
+    poll: Duration,
+) -> Result<PathBuf> {
+    let dir = source.parent().unwrap_or(Path::new("."));
+    let stem = source
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    println!("Waiting for a new document in directory: {}", dir.display());
+    loop {
+        sleep(poll).await;
+        let candidates = snapshot_dir(dir)
+            .await?
+            .into_iter()
+            .filter(|(path, modified)| path != source && before.get(path) != Some(modified))
+            .collect();
+        if let Some(output) = pick_output(candidates, &stem) {
+            println!("Document detected at path: {}", output.display());
+            settle(&output, grace).await?;
+            return Ok(output);
+        }
+    }
+}
+
+pub(super) async fn watch_outputs(
+    watched: &[(PathBuf, Option<SystemTime>)],
+    grace: Duration,
+    poll: Duration,
+    retries: u32,
+    timeout: Option<Duration>,
+) -> Vec<Result<()>> {
+    let mut watchers = JoinSet::new();
+    for (output, baseline) in watched.iter().cloned().collect::<BTreeMap<_, _>>() {
+        watchers.spawn(async move {
+            let result = match watch_output(output.clone(), baseline, grace, poll).await {
+                Ok(()) => validate_settled(&output, grace, poll, retries).await,
+                Err(error) => Err(error),
+            };
+            (output, result)
+        });
+    }
+
+    // A timeout too long to add to the clock is as good as none.
+    let deadline = timeout.and_then(|timeout| Instant::now().checked_add(timeout));
+    let total = watchers.len();
+    let mut finished = HashMap::new();
+    loop {
+        let joined = match deadline {
+            Some(deadline) => match time::timeout_at(deadline, watchers.join_next()).await {
+                Ok(joined) => joined,
+                Err(_) => break,
+            },
+            None => watchers.join_next().await,
+        };
+        let Some(joined) = joined else { break };
+        let Ok((output, result)) = joined else {
+            continue;
+        };
+        match &result {
+            Ok(()) => println!(
+                "Output {} of {} complete: {}",
+                finished.len() + 1,
+                total,
+                output.display()
+            ),
+            Err(error) => println!("Output at path {} failed: {}", output.display(), error),
+        }
+        finished.insert(output, result);
+    }
+    watchers.abort_all();
+
+    let missing: Vec<&PathBuf> = watched
+        .iter()
+        .map(|(output, _)| output)
+        .filter(|output| !finished.contains_key(*output))
+        .collect();
+    if !missing.is_empty() {
+        println!("Outputs that never appeared:");
+        for output in &missing {
+            println!("  {}", output.display());
+        }
+    }
+
+    watched
+        .iter()
+        .map(|(output, _)| match finished.remove(output) {
+            Some(result) => result,
+            None => Err(anyhow!(
+                "Document never appeared at path: {}",
+                output.display()
+            )),
+        })
+        .collect()
+}
+
+pub(super) fn output_dir(source: &Path) -> &Path {
+    match source.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    }
+}
+
+pub(super) async fn check_writable(dir: &Path) -> Result<()> {
+    if !fs::metadata(dir)
+        .await
+        .is_ok_and(|metadata| metadata.is_dir())
+    {
+        return Err(anyhow!("Output directory {} does not exist", dir.display()));
+    }
+    let probe = dir.join(format!(".umi-http-{}.tmp", process::id()));
+    fs::write(&probe, b"").await.map_err(|error| {
+        anyhow!(
+            "Output directory {} is not writable: {}",
+            dir.display(),
+            error
+        )
+    })?;
+    fs::remove_file(&probe)
+        .await
+        .map_err(|error| anyhow!("Error removing write probe {}: {}", probe.display(), error))
+}
+
+pub(super) async fn validate_output(path: &Path) -> Result<()> {
+    let metadata = fs::metadata(path).await.map_err(|error| {
+        anyhow!(
+            "Output document at path {} is missing: {}",
+            path.display(),
+            error
+        )
+    })?;
+    if metadata.len() == 0 {
+        return Err(anyhow!(
+            "Output document at path {} is empty",
+            path.display()
+        ));
+    }
+    if path
+        .extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("pdf"))
+    {
+        let mut header = [0u8; 5];
+        fs::File::open(path).await?.read_exact(&mut header).await?;
+        if &header != b"%PDF-" {
+            return Err(anyhow!(
+                "Output document at path {} is not a valid PDF",
+                path.display()
+            ));
+        }
+    }
+    Ok(())
+}
+
+pub(super) async fn validate_settled(
+    path: &Path,
+    grace: Duration,
+    poll: Duration,
+    retries: u32,
+) -> Result<()> {
+    let mut retry = 0;
+    loop {
+        match validate_output(path).await {
+            Ok(()) => return Ok(()),
+            Err(error) if retry < retries => {
+                retry += 1;
+                println!(
+                    "{}. Waiting for it to settle ({}/{})...",
+                    error, retry, retries
+                );
+                sleep(poll).await;
+                settle(path, grace).await?;
+            }
+            Err(error) => return Err(error),
+        }
+    }
+}
+
+pub(super) fn same_file(a: &Path, b: &Path) -> bool {
+    match (a.canonicalize(), b.canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+pub(super) async fn watch_limits(config: &RunConfig, inputs: &[impl AsRef<Path>]) -> PhaseLimits {
+    if config.timeout_per_page.is_none() {
+        return config.watch;
+    }
+    let mut pages = Some(0);
+    for input in inputs {
+        pages = match (pages, pdf::page_count(input.as_ref()).await) {
+            (Some(total), Ok(count)) => Some(total + count),
+            _ => None,
+        };
+    }
+    let limits = config.watch_for(pages);
+    if let (Some(pages), Some(timeout)) = (pages, limits.timeout) {
+        println!(
+            "Allowing {}s for {} pages to be processed.",
+            timeout.as_secs(),
+            pages
+        );
+    }
+    limits
+}
+
+pub(super) fn computed_output(args: &Args, source: &Path) -> PathBuf {
+    paths::output_path_as(
+        source,
+        output_format(args, source).unwrap_or(OutputFormat::LayeredPdf),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+
+    const GRACE: Duration = Duration::from_millis(500);
+    const POLL: Duration = Duration::from_secs(1);
+
+    /// An empty directory for one test, removed again by `remove_dir_all`.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("umi-http-{}-{}", name, process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Writes `contents` to `path` once `delay` has passed.
+    fn write_after(delay: Duration, path: &Path, contents: &'static [u8]) {
+        let path = path.to_path_buf();
+        tokio::spawn(async move {
+            sleep(delay).await;
+            std::fs::write(path, contents).unwrap();
+        });
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn watch_output_waits_for_the_document_to_appear() {
+        let dir = test_dir("watch-new");
+        let output = dir.join("a.layered.pdf");
+        write_after(Duration::from_millis(2500), &output, b"%PDF-1.7");
+        let started = Instant::now();
+        watch_output(output.clone(), None, GRACE, POLL)
+            .await
+            .unwrap();
+        assert_eq!(started.elapsed(), POLL * 3 + GRACE);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn watch_output_waits_for_an_existing_document_to_change() {
+        let dir = test_dir("watch-existing");
+        let output = dir.join("a.layered.pdf");
+        std::fs::write(&output, b"%PDF-1.7 old").unwrap();
+        let baseline = modified(&output).await;
+        let file = File::options().write(true).open(&output).unwrap();
+        tokio::spawn(async move {
+            sleep(Duration::from_millis(2500)).await;
+            file.set_modified(SystemTime::now() + Duration::from_secs(10))
+                .unwrap();
+        });
+        let started = Instant::now();
+        watch_output(output.clone(), baseline, GRACE, POLL)
+            .await
+            .unwrap();
+        assert_eq!(started.elapsed(), POLL * 3 + GRACE);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn watch_outputs_treats_an_endless_timeout_as_none() {
+        let dir = test_dir("watch-endless");
+        let output = dir.join("a.layered.pdf");
+        write_after(Duration::from_millis(2500), &output, b"%PDF-1.7");
+        let results = watch_outputs(
+            &[(output.clone(), None)],
+            GRACE,
+            POLL,
+            0,
+            Some(Duration::MAX),
+        )
+        .await;
+        assert!(results[0].is_ok(), "{:?}", results[0]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn settle_waits_until_the_size_stops_changing() {
+        let dir = test_dir("settle");
+        let output = dir.join("a.layered.pdf");
+        std::fs::write(&output, b"%PDF-").unwrap();
+        write_after(Duration::from_millis(300), &output, b"%PDF-1.7 more");
+        let started = Instant::now();
+        settle(&output, GRACE).await.unwrap();
+        assert_eq!(started.elapsed(), GRACE * 2);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn settle_without_grace_returns_at_once() {
+        let started = Instant::now();
+        settle(Path::new("missing.pdf"), Duration::ZERO)
+            .await
+            .unwrap();
+        assert_eq!(started.elapsed(), Duration::ZERO);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn validate_settled_waits_for_an_incomplete_output() {
+        let dir = test_dir("validate-incomplete");
+        let output = dir.join("a.layered.pdf");
+        std::fs::write(&output, b"").unwrap();
+        write_after(Duration::from_millis(1200), &output, b"%PDF-1.7");
+        let started = Instant::now();
+        validate_settled(&output, GRACE, POLL, 2).await.unwrap();
+        assert_eq!(started.elapsed(), POLL + GRACE * 2);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn validate_settled_gives_up_after_its_retries() {
+        let dir = test_dir("validate-invalid");
+        let output = dir.join("a.layered.pdf");
+        std::fs::write(&output, b"not a pdf").unwrap();
+        let started = Instant::now();
+        let error = validate_settled(&output, GRACE, POLL, 2).await.unwrap_err();
+        assert!(
+            error.to_string().ends_with("is not a valid PDF"),
+            "{}",
+            error
+        );
+        assert_eq!(started.elapsed(), (POLL + GRACE) * 2);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn validate_settled_reports_a_missing_output() {
+        let error = validate_settled(Path::new("missing.layered.pdf"), GRACE, POLL, 0)
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("is missing"), "{}", error);
+    }
+}
@@ -0,0 +1,476 @@
+use super::limits::{cancellable, check_cancelled, pause, with_limits, with_timeout};
+use super::output::has_output_extension;
+use super::{Args, TAB_NAME};
+use crate::argv::ArgvCommand;
+use crate::config::RunConfig;
+use crate::paths::OutputFormat;
+use crate::server::Server;
+use anyhow::{anyhow, Result};
+use clap::ValueEnum;
+use regex::Regex;
+use serde_json::{json, Value};
+use std::collections::BTreeSet;
+use std::future::Future;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::time::{self, sleep};
+use tracing::instrument;
+
+pub(super) async fn tabs(server: &Server) -> Result<String> {
+    server.send_request(ArgvCommand::all_pages()).await
+}
+
+fn tab_limit_error() -> String {
+    format!(
+        "Could not open a new {} tab; close some tabs first (leftover {} tabs can be closed with `umi-http clean`)",
+        TAB_NAME, TAB_NAME
+    )
+}
+
+#[instrument(name = "open", skip_all, fields(server = server.address()), err(Display))]
+async fn open_batch_ocr(server: &Server, page_type: u16, config: &RunConfig) -> Result<()> {
+    println!("Opening Batch OCR...");
+    let response = with_limits("Opening Batch OCR", config.open, config, || {
+        server.send_request(ArgvCommand::add_page(page_type))
+    })
+    .await?;
+    let lowercase = response.to_lowercase();
+    if lowercase.contains("error") || lowercase.contains("fail") {
+        return Err(anyhow!("{}: {}", tab_limit_error(), response.trim()));
+    }
+    println!("Batch OCR opened.");
+    Ok(())
+}
+
+#[instrument(name = "close", skip(server), fields(server = server.address()), err(Display))]
+async fn close_batch_ocr(server: &Server, index: u16) -> Result<()> {
+    println!("Closing Batch OCR with index {}...", index);
+    server.send_request(ArgvCommand::del_page(index)).await?;
+    println!("Batch OCR with index {} closed.", index);
+    Ok(())
+}
+
+/// Number of documents in the BatchDOC queue, if the server reports it.
+///
+/// Older Umi-OCR versions answer getDocCount with an error. That is detected
+/// on the first query, noted once, and the queue is not queried again.
+async fn doc_count(server: &Server) -> Result<Option<usize>> {
+    let query = || server.send_request(ArgvCommand::call_qml(TAB_NAME).func("getDocCount"));
+    let mut first = None;
+    let supported = server
+        .supports_status(|| async {
+            let response = query().await?;
+            let count = response.trim().parse().ok();
+            if count.is_none() {
+                println!(
+                    "Umi-OCR at {} does not report the {} queue length ({}). Skipping queue checks and reports.",
+                    server.address(),
+                    TAB_NAME,
+                    response.trim()
+                );
+            }
+            first = Some(count);
+            Ok(count.is_some())
+        })
+        .await?;
+    if let Some(count) = first {
+        return Ok(count);
+    }
+    if !supported {
+        return Ok(None);
+    }
+    Ok(query().await?.trim().parse().ok())
+}
+
+pub(super) async fn heartbeat<T>(
+    args: &Args,
+    server: &Server,
+    future: impl Future<Output = Result<T>>,
+) -> Result<T> {
+    let Some(seconds) = args.heartbeat else {
+        return future.await;
+    };
+    let every = Duration::from_secs(seconds.max(1));
+    let mut interval = time::interval(every);
+    interval.tick().await;
+    tokio::pin!(future);
+    loop {
+        tokio::select! {
+            result = &mut future => return result,
+            _ = interval.tick() => {
+                if let Err(error) = with_timeout("Heartbeat", Some(every), tabs(server)).await {
+                    tracing::warn!(%error, "heartbeat failed");
+                    if server.is_healthy() {
+                        println!("{}. Still waiting...", error);
+                        continue;
+                    }
+                    return Err(anyhow!(
+                        "Umi-OCR at {} became unreachable during processing: {}",
+                        server.address(),
+                        error
+                    ));
+                }
+            }
+        }
+    }
+}
+
+pub(super) async fn report_queue<T>(
+    args: &Args,
+    server: &Server,
+    future: impl Future<Output = T>,
+) -> T {
+    let Some(seconds) = args.queue_interval else {
+        return future.await;
+    };
+    let mut interval = time::interval(Duration::from_secs(seconds.max(1)));
+    interval.tick().await;
+    tokio::pin!(future);
+    loop {
+        tokio::select! {
+            value = &mut future => return value,
+            _ = interval.tick() => match doc_count(server).await {
+                Ok(Some(count)) => println!(
+                    "{} documents left in the {} queue at {}.",
+                    count,
+                    TAB_NAME,
+                    server.address()
+                ),
+                Ok(None) => {}
+                Err(error) => println!("Could not query the {} queue: {}", TAB_NAME, error),
+            },
+        }
+    }
+}
+
+/// Enables exactly `formats` in the BatchDOC tab, leaving the settings alone
+/// when no format was asked for.
+pub(super) async fn set_formats(
+    server: &Server,
+    config: &RunConfig,
+    formats: &BTreeSet<OutputFormat>,
+) -> Result<()> {
+    if formats.is_empty() {
+        return Ok(());
+    }
+    let names: Vec<String> = formats
+        .iter()
+        .filter_map(|format| format.to_possible_value())
+        .map(|value| value.get_name().to_string())
+        .collect();
+    println!("Selecting output format {}...", names.join(", "));
+    for format in OutputFormat::ALL {
+        let command = ArgvCommand::call_qml(TAB_NAME)
+            .func("setValue")
+            .arg(format.setting())
+            .json_arg(json!(formats.contains(&format)));
+        with_limits("Selecting output format", config.start, config, || {
+            server.send_request(command.clone())
+        })
+        .await?;
+    }
+    Ok(())
+}
+
+#[instrument(
+    name = "add",
+    skip_all,
+    fields(server = server.address(), count = paths.len()),
+    err(Display)
+)]
+pub(super) async fn add_docs(server: &Server, paths: &[String], config: &RunConfig) -> Result<()> {
+    match paths {
+        [path] => println!("Adding document from path {}...", path),
+        _ => println!("Adding {} documents...", paths.len()),
+    }
+    let before = doc_count(server).await?;
+    let command = ArgvCommand::call_qml(TAB_NAME)
+        .func("addDocs")
+        .json_arg(json!(paths));
+    with_limits("Adding documents", config.add, config, || {
+        server.send_request(command.clone())
+    })
+    .await?;
+    match (before, doc_count(server).await?) {
+        (Some(before), Some(after)) if after < before + paths.len() => {
+            let queued = after.saturating_sub(before);
+            return Err(match paths {
+                [path] => anyhow!(
+                    "addDocs reported success but no documents were queued for {}",
+                    path
+                ),
+                _ => anyhow!(
+                    "addDocs reported success but only {} of {} documents were queued",
+                    queued,
+                    paths.len()
+                ),
+            });
+        }
+        (Some(_), Some(_)) => {}
+        _ if server.reports_status() => {
+            println!("Document count unavailable. Skipping the queue check.")
+        }
+        _ => {}
+    }
+    println!("Documents added.");
+    Ok(())
+}
+
+#[instrument(name = "start", skip_all, fields(server = server.address()), err(Display))]
+pub(super) async fn doc_start(server: &Server, config: &RunConfig) -> Result<Vec<PathBuf>> {
+    println!("Starting document processing...");
+    let response = with_limits("Starting document processing", config.start, config, || {
+        server.send_request(ArgvCommand::call_qml(TAB_NAME).func("docStart"))
+    })
+    .await?;
+    println!("Document processing started.");
+    Ok(reported_paths(&response))
+}
+
+fn collect_strings(value: &Value, found: &mut Vec<String>) {
+    match value {
+        Value::String(string) => found.push(string.clone()),
+        Value::Array(values) => values
+            .iter()
+            .for_each(|value| collect_strings(value, found)),
+        Value::Object(map) => map.values().for_each(|value| collect_strings(value, found)),
+        _ => {}
+    }
+}
+
+/// Output paths named in a `docStart` response, given either as JSON or as
+/// plain text with one path per line.
+fn reported_paths(response: &str) -> Vec<PathBuf> {
+    let mut found = Vec::new();
+    match serde_json::from_str::<Value>(response) {
+        Ok(value) => collect_strings(&value, &mut found),
+        Err(_) => found.extend(response.lines().map(|line| line.trim().to_string())),
+    }
+    found
+        .into_iter()
+        .map(PathBuf::from)
+        .filter(|path| path.is_absolute() && has_output_extension(path))
+        .collect()
+}
+
+#[instrument(name = "verify", skip_all, fields(server = server.address()), err(Display))]
+async fn verify(server: &Server, config: &RunConfig) -> Result<()> {
+    let regex = Regex::new(&format!(r"{}_\d+", TAB_NAME))?;
+    for attempt in 1..=config.verify.retries + 1 {
+        check_cancelled(config)?;
+        let tabs = cancellable(
+            &config.cancel,
+            with_timeout("Listing tabs", config.verify.timeout, tabs(server)),
+        )
+        .await?;
+        if regex.find(&tabs).is_some() {
+            println!("{} found on attempt {}.", TAB_NAME, attempt);
+            return Ok(());
+        }
+        if attempt <= config.verify.retries {
+            config.retry_budget.take().map_err(|error| {
+                anyhow!("{}: {}: {} not found", tab_limit_error(), error, TAB_NAME)
+            })?;
+        }
+        println!("{} not found on attempt {}. Retrying...", TAB_NAME, attempt);
+        pause(config, config.retry_delay).await?;
+    }
+    Err(anyhow!(
+        "{}: Max attempts reached for {}. Tab now found.",
+        tab_limit_error(),
+        TAB_NAME
+    ))
+}
+
+#[instrument(name = "verify", skip(server), fields(server = server.address()), err(Display))]
+async fn verify_page(server: &Server, index: u16) -> Result<()> {
+    let regex = Regex::new(&format!(r"(?m)^{}\s+{}_", index, TAB_NAME))?;
+    if regex.find(&tabs(server).await?).is_none() {
+        return Err(anyhow!("Tab {} is not a {} page.", index, TAB_NAME));
+    }
+    println!("Reusing {} with index {}.", TAB_NAME, index);
+    Ok(())
+}
+
+pub(super) async fn close_batch_tabs(server: &Server, config: &RunConfig) -> Result<usize> {
+    let re = Regex::new(r"(?m)^(\d+)\s+BatchDOC_").unwrap();
+    let indices: Vec<u16> = re
+        .captures_iter(&tabs(server).await?)
+        .filter_map(|cap| cap.get(1).and_then(|index| index.as_str().parse().ok()))
+        .collect();
+
+    for index in indices.iter().rev() {
+        close_batch_ocr(server, *index).await?;
+        sleep(config.close_delay).await;
+    }
+    Ok(indices.len())
+}
+
+#[instrument(
+    name = "tab",
+    skip_all,
+    fields(server = server.address(), index = args.reuse_page),
+    err(Display)
+)]
+pub(super) async fn prepare_tab(args: &Args, config: &RunConfig, server: &Server) -> Result<()> {
+    match args.reuse_page {
+        Some(index) => verify_page(server, index).await,
+        None => {
+            close_batch_tabs(server, config).await?;
+            open_batch_ocr(server, args.page_type, config).await?;
+            pause(config, config.open_delay).await?;
+            verify(server, config).await
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{PhaseLimits, RetryBudget};
+    use crate::run::limits::Cancelled;
+    use crate::server::ClientArgs;
+    use reqwest::header::HeaderValue;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::time::Instant;
+
+    const HOME: &str = "1 Home_1\n";
+    const BATCH: &str = "1 Home_1\n2 BatchDOC_1\n";
+
+    fn config(verify: PhaseLimits) -> RunConfig {
+        RunConfig {
+            verify,
+            retry_delay: Duration::from_secs(1),
+            ..RunConfig::default()
+        }
+    }
+
+    async fn read_request(stream: &mut TcpStream) {
+        let mut request = Vec::new();
+        let mut buffer = [0; 1024];
+        loop {
+            let read = stream.read(&mut buffer).await.unwrap();
+            request.extend_from_slice(&buffer[..read]);
+            let text = String::from_utf8_lossy(&request);
+            if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                let length = head
+                    .lines()
+                    .find_map(|line| {
+                        let (name, value) = line.split_once(':')?;
+                        name.eq_ignore_ascii_case("content-length")
+                            .then(|| value.trim().parse::<usize>().unwrap())
+                    })
+                    .unwrap_or(0);
+                if body.len() >= length {
+                    return;
+                }
+            }
+            if read == 0 {
+                return;
+            }
+        }
+    }
+
+    /// A server answering one request per entry of `responses`, in order;
+    /// `None` accepts the request and never answers it.
+    async fn umi_ocr(responses: Vec<Option<&'static str>>) -> Server {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let mut pending = Vec::new();
+            for response in responses {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                read_request(&mut stream).await;
+                let Some(body) = response else {
+                    pending.push(stream);
+                    continue;
+                };
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+            sleep(Duration::MAX).await;
+        });
+        let client = ClientArgs {
+            compress: false,
+            content_type: HeaderValue::from_static("application/json"),
+            user_agent: "umi-http-test".to_string(),
+            pool_max_idle_per_host: None,
+            pool_idle_timeout: None,
+            force_status: false,
+        };
+        Server::new(&address, &client).unwrap()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn verify_lists_tabs_again_until_the_tab_shows_up() {
+        let server = umi_ocr(vec![Some(HOME), Some(HOME), Some(BATCH)]).await;
+        let config = config(PhaseLimits {
+            timeout: None,
+            retries: 2,
+        });
+        let started = Instant::now();
+        verify(&server, &config).await.unwrap();
+        assert_eq!(started.elapsed(), config.retry_delay * 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn verify_suggests_closing_tabs_when_the_tab_never_shows_up() {
+        let server = umi_ocr(vec![Some(HOME), Some(HOME)]).await;
+        let config = config(PhaseLimits {
+            timeout: None,
+            retries: 1,
+        });
+        let error = verify(&server, &config).await.unwrap_err().to_string();
+        assert!(error.starts_with(&tab_limit_error()), "{}", error);
+        assert!(error.ends_with("Max attempts reached for BatchDOC. Tab now found."));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn verify_stops_once_the_retry_budget_is_spent() {
+        let server = umi_ocr(vec![Some(HOME)]).await;
+        let config = RunConfig {
+            retry_budget: RetryBudget::new(Some(0)),
+            ..config(PhaseLimits {
+                timeout: None,
+                retries: 2,
+            })
+        };
+        let error = verify(&server, &config).await.unwrap_err().to_string();
+        assert!(
+            error.ends_with("Retry budget of 0 exhausted: BatchDOC not found"),
+            "{}",
+            error
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn verify_times_out_a_listing_without_the_tab_hint() {
+        let server = umi_ocr(vec![None]).await;
+        let config = config(PhaseLimits {
+            timeout: Some(Duration::from_secs(5)),
+            retries: 2,
+        });
+        let started = Instant::now();
+        let error = verify(&server, &config).await.unwrap_err();
+        assert_eq!(error.to_string(), "Listing tabs timed out after 5s");
+        assert_eq!(started.elapsed(), Duration::from_secs(5));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn verify_returns_cancelled_while_a_listing_is_pending() {
+        let server = umi_ocr(vec![None]).await;
+        let config = config(PhaseLimits::default());
+        let cancel = config.cancel.clone();
+        tokio::spawn(async move {
+            sleep(Duration::from_secs(1)).await;
+            cancel.cancel();
+        });
+        let error = verify(&server, &config).await.unwrap_err();
+        assert!(error.is::<Cancelled>(), "{}", error);
+    }
+}
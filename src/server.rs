@@ -0,0 +1,291 @@
+use crate::argv::ArgvCommand;
+use anyhow::{anyhow, Result};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use reqwest::header::{HeaderMap, HeaderValue, CONTENT_ENCODING, CONTENT_TYPE};
+use reqwest::Client;
+use std::future::Future;
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tokio::sync::OnceCell;
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct ClientArgs {
+    /// Gzip request bodies (for remote servers that accept `Content-Encoding: gzip`)
+    #[arg(long)]
+    pub compress: bool,
+    /// Content-Type header sent with every request
+    #[arg(long, value_name = "TYPE", default_value = "application/json", value_parser = parse_header)]
+    pub content_type: HeaderValue,
+    /// User-Agent header sent with every request
+    #[arg(long, value_name = "AGENT", default_value = USER_AGENT)]
+    pub user_agent: String,
+    /// Idle connections kept open to each server for reuse. Each server is
+    /// driven by one worker sending one request at a time, so a few are
+    /// enough [default: no limit]
+    #[arg(long, value_name = "COUNT")]
+    pub pool_max_idle_per_host: Option<usize>,
+    /// Seconds an idle connection is kept before it is closed [default: 90]
+    #[arg(long, value_name = "SECONDS")]
+    pub pool_idle_timeout: Option<u64>,
+    /// Keep querying the BatchDOC queue length even if the server's first
+    /// answer suggests it does not support it
+    #[arg(long)]
+    pub force_status: bool,
+}
+
+const USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+
+fn parse_header(value: &str) -> Result<HeaderValue, String> {
+    HeaderValue::from_str(value).map_err(|error| error.to_string())
+}
+
+pub struct Server {
+    address: String,
+    url: String,
+    client: Client,
+    compress: bool,
+    healthy: AtomicBool,
+    status: OnceCell<bool>,
+}
+
+const SNIPPET_LEN: usize = 80;
+
+fn charset(content_type: Option<&HeaderValue>) -> Option<String> {
+    content_type?
+        .to_str()
+        .ok()?
+        .split(';')
+        .filter_map(|param| param.trim().split_once('='))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("charset"))
+        .map(|(_, value)| value.trim().trim_matches('"').to_string())
+}
+
+/// Decodes a response body, quoting the start of it if it is not UTF-8.
+fn decode(address: &str, charset: Option<&str>, bytes: Vec<u8>) -> Result<String> {
+    String::from_utf8(bytes).map_err(|error| {
+        let snippet: String = String::from_utf8_lossy(error.as_bytes())
+            .chars()
+            .take(SNIPPET_LEN)
+            .collect();
+        anyhow!(
+            "Response from Umi-OCR at {} is not valid UTF-8 (charset: {}): {:?}",
+            address,
+            charset.unwrap_or("not given"),
+            snippet
+        )
+    })
+}
+
+fn gzip(body: &[u8]) -> Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(body)?;
+    Ok(encoder.finish()?)
+}
+
+impl Server {
+    pub fn new(address: &str, args: &ClientArgs) -> Result<Self> {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, args.content_type.clone());
+        let mut builder = Client::builder()
+            .default_headers(headers)
+            .user_agent(&args.user_agent);
+        if let Some(max) = args.pool_max_idle_per_host {
+            builder = builder.pool_max_idle_per_host(max);
+        }
+        if let Some(seconds) = args.pool_idle_timeout {
+            builder = builder.pool_idle_timeout(Duration::from_secs(seconds));
+        }
+        let client = builder
+            .build()
+            .map_err(|error| anyhow!("Error creating HTTP client: {}", error))?;
+        Ok(Self {
+            address: address.to_string(),
+            url: format!("http://{}/argv", address),
+            client,
+            compress: args.compress,
+            healthy: AtomicBool::new(true),
+            status: match args.force_status {
+                true => OnceCell::new_with(Some(true)),
+                false => OnceCell::new(),
+            },
+        })
+    }
+
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::Relaxed)
+    }
+
+    /// Whether the server answers BatchDOC status queries, as found by
+    /// `detect` the first time it succeeds.
+    pub async fn supports_status<F>(&self, detect: impl FnOnce() -> F) -> Result<bool>
+    where
+        F: Future<Output = Result<bool>>,
+    {
+        self.status.get_or_try_init(detect).await.copied()
+    }
+
+    /// False once the server has been found not to answer status queries.
+    pub fn reports_status(&self) -> bool {
+        self.status.get() != Some(&false)
+    }
+
+    pub async fn send_request(&self, command: ArgvCommand) -> Result<String> {
+        let argv = command.into_value();
+        tracing::debug!(server = %self.address, %argv, "request");
+        let body = serde_json::to_vec(&argv)?;
+        let request = self.client.post(&self.url);
+        let request = if self.compress {
+            request.header(CONTENT_ENCODING, "gzip").body(gzip(&body)?)
+        } else {
+            request.body(body)
+        };
+        let response = request.send().await.map_err(|error| {
+            if error.is_connect() {
+                self.healthy.store(false, Ordering::Relaxed);
+            }
+            anyhow!(
+                "Error sending request to Umi-OCR at {}: {}",
+                self.address,
+                error
+            )
+        })?;
+        let status = response.status();
+        let charset = charset(response.headers().get(CONTENT_TYPE));
+        let bytes = response.bytes().await.map_err(|error| {
+            anyhow!(
+                "Error reading response from Umi-OCR at {}: {}",
+                self.address,
+                error
+            )
+        })?;
+        let text = decode(&self.address, charset.as_deref(), bytes.to_vec())?;
+        tracing::debug!(server = %self.address, %status, body = ?text, "response");
+        Ok(text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// Accepts one request, answers it with `ok` and returns its head and
+    /// raw body.
+    async fn capture_request(listener: TcpListener) -> (String, Vec<u8>) {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut request = Vec::new();
+        let mut buffer = [0; 1024];
+        let (head, body) = loop {
+            let read = stream.read(&mut buffer).await.unwrap();
+            assert!(read > 0, "connection closed before the request ended");
+            request.extend_from_slice(&buffer[..read]);
+            let Some(end) = request.windows(4).position(|window| window == b"\r\n\r\n") else {
+                continue;
+            };
+            let head = String::from_utf8(request[..end].to_vec()).unwrap();
+            let length = head
+                .lines()
+                .find_map(|line| {
+                    let (name, value) = line.split_once(':')?;
+                    name.eq_ignore_ascii_case("content-length")
+                        .then(|| value.trim().parse::<usize>().unwrap())
+                })
+                .unwrap_or(0);
+            if request.len() >= end + 4 + length {
+                break (head, request[end + 4..end + 4 + length].to_vec());
+            }
+        };
+        let response = "HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok";
+        stream.write_all(response.as_bytes()).await.unwrap();
+        (head, body)
+    }
+
+    #[tokio::test]
+    async fn send_request_gzips_the_argv_when_compressing() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let request = tokio::spawn(capture_request(listener));
+        let client = ClientArgs {
+            compress: true,
+            content_type: HeaderValue::from_static("application/json"),
+            user_agent: "umi-http-test".to_string(),
+            pool_max_idle_per_host: None,
+            pool_idle_timeout: None,
+            force_status: false,
+        };
+        let server = Server::new(&address, &client).unwrap();
+        let command = ArgvCommand::call_qml("BatchDOC")
+            .func("addDocs")
+            .json_arg(serde_json::json!(["识别.pdf"]));
+        assert_eq!(server.send_request(command.clone()).await.unwrap(), "ok");
+
+        let (head, body) = request.await.unwrap();
+        assert!(
+            head.lines()
+                .any(|line| line.eq_ignore_ascii_case("content-encoding: gzip")),
+            "{}",
+            head
+        );
+        let mut argv = Vec::new();
+        GzDecoder::new(body.as_slice())
+            .read_to_end(&mut argv)
+            .unwrap();
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(&argv).unwrap(),
+            command.into_value()
+        );
+    }
+
+    #[test]
+    fn charset_reads_the_content_type_parameter() {
+        let header = HeaderValue::from_static("text/plain; charset=utf-8");
+        assert_eq!(charset(Some(&header)).as_deref(), Some("utf-8"));
+        let header = HeaderValue::from_static("text/plain;Charset=\"GBK\"");
+        assert_eq!(charset(Some(&header)).as_deref(), Some("GBK"));
+    }
+
+    #[test]
+    fn charset_is_none_when_not_given() {
+        assert_eq!(charset(None), None);
+        let header = HeaderValue::from_static("application/json");
+        assert_eq!(charset(Some(&header)), None);
+    }
+
+    #[test]
+    fn decode_returns_valid_bodies() {
+        let text = decode("127.0.0.1:1224", None, "识别 done".as_bytes().to_vec()).unwrap();
+        assert_eq!(text, "识别 done");
+    }
+
+    #[test]
+    fn decode_quotes_the_start_of_invalid_bodies() {
+        let mut body = b"\xff\xfe".to_vec();
+        body.extend("x".repeat(200).into_bytes());
+        let error = decode("127.0.0.1:1224", Some("GBK"), body)
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("127.0.0.1:1224"), "{}", error);
+        assert!(error.contains("charset: GBK"), "{}", error);
+        let snippet = format!(
+            "\"{}{}\"",
+            "\u{fffd}".repeat(2),
+            "x".repeat(SNIPPET_LEN - 2)
+        );
+        assert!(error.ends_with(&snippet), "{}", error);
+    }
+
+    #[test]
+    fn decode_says_when_no_charset_was_given() {
+        let error = decode("127.0.0.1:1224", None, vec![0xc3]).unwrap_err();
+        assert!(error.to_string().contains("charset: not given"));
+    }
+}